@@ -1,14 +1,20 @@
 use std::collections::HashMap;
 
-use futures::future::join_all;
+use ic_cdk::api::time;
 use ic_cdk::export::candid::Principal;
+use ic_cdk::storage::{stable_restore, stable_save};
 use ic_cdk::{caller, trap};
-use ic_cdk_macros::{init, query, update};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 
-use union_utils::fns::{log, send_events};
-use union_utils::types::{Account, OnMoveListener, OnMoveListenersInfo};
+use union_utils::fns::{deliver_pending_events, log};
+use union_utils::types::{
+    Account, ListenerBacklog, OnMoveListener, OnMoveListenersInfo, TokenMoveEvent,
+    TokenMoveEventLog,
+};
 
-use crate::utils::{ClaimToken, ClaimTokenInfo, ClaimTokenInitPayload, Controllers, Error};
+use crate::utils::{
+    ClaimToken, ClaimTokenInfo, ClaimTokenInitPayload, Controllers, Error, VersionedClaimToken,
+};
 
 mod utils;
 
@@ -26,6 +32,7 @@ fn init(payload: ClaimTokenInitPayload) {
         claims: HashMap::new(),
         total_supply: 0,
         on_move_listeners: OnMoveListenersInfo::default(),
+        event_log: TokenMoveEventLog::default(),
         info: payload.info,
         controllers: c,
     };
@@ -47,6 +54,29 @@ fn init(payload: ClaimTokenInitPayload) {
     }
 }
 
+#[pre_upgrade]
+fn pre_upgrade_hook() {
+    log("claim_token.pre_upgrade()");
+
+    let token = unsafe { TOKEN.take().unwrap() };
+
+    stable_save((VersionedClaimToken::V2(token),))
+        .expect("Unable to save the token to stable memory");
+}
+
+#[post_upgrade]
+fn post_upgrade_hook() {
+    log("claim_token.post_upgrade()");
+
+    let (versioned,): (VersionedClaimToken,) =
+        stable_restore().expect("Unable to restore the token from stable memory");
+
+    let mut token = versioned.into_latest();
+    token.on_move_listeners.reset_in_flight_deliveries();
+
+    unsafe { TOKEN = Some(token) }
+}
+
 #[query]
 fn has_claim(token_holder: Principal) -> bool {
     log("claim_token.has_claim()");
@@ -136,19 +166,12 @@ async fn issue(recipients: Vec<Principal>) -> Vec<Result<(), Error>> {
 
     let results: Vec<_> = recipients
         .into_iter()
-        .map(|to| token.issue(to, caller()))
-        .map(|res| async {
-            match res {
-                Ok(ev_n_list) => {
-                    send_events(ev_n_list).await;
-                    Ok(())
-                }
-                Err(e) => Err(e),
-            }
-        })
+        .map(|to| token.issue(to, caller(), time()).map(|_| ()))
         .collect();
 
-    join_all(results).await
+    deliver_on_move_events().await;
+
+    results
 }
 
 #[update]
@@ -159,19 +182,12 @@ async fn revoke(holders: Vec<Principal>) -> Vec<Result<(), Error>> {
 
     let results: Vec<_> = holders
         .into_iter()
-        .map(|from| token.revoke(from, caller()))
-        .map(|res| async {
-            match res {
-                Ok(ev_n_list) => {
-                    send_events(ev_n_list).await;
-                    Ok(())
-                }
-                Err(e) => Err(e),
-            }
-        })
+        .map(|from| token.revoke(from, caller(), time()).map(|_| ()))
         .collect();
 
-    join_all(results).await
+    deliver_on_move_events().await;
+
+    results
 }
 
 #[update]
@@ -197,3 +213,45 @@ fn unsubscribe_on_move(listener_ids: Vec<u64>) -> Vec<Result<OnMoveListener, Err
         .map(|listener_id| token.unsubscribe_on_move(listener_id, caller()))
         .collect()
 }
+
+#[query]
+fn get_events(from_seq: u64, limit: u64) -> Vec<TokenMoveEvent> {
+    log("claim_token.get_events()");
+
+    let token = unsafe { TOKEN.as_ref().unwrap() };
+
+    token.event_log.get_events(from_seq, limit)
+}
+
+#[query]
+fn on_move_backlog() -> Vec<ListenerBacklog> {
+    log("claim_token.on_move_backlog()");
+
+    let token = unsafe { TOKEN.as_ref().unwrap() };
+
+    token.on_move_listeners.get_backlog()
+}
+
+#[update]
+async fn enable_on_move_listener(listener_id: u64) -> Result<(), Error> {
+    log("claim_token.enable_on_move_listener()");
+
+    let token = unsafe { TOKEN.as_mut().unwrap() };
+    token.enable_on_move_listener(listener_id, caller())?;
+
+    deliver_on_move_events().await;
+
+    Ok(())
+}
+
+// claims are issued rarely, so retries can't wait for the next one - the heartbeat sends the due
+// deliveries (ic-cdk-macros has no attribute for it yet)
+#[export_name = "canister_heartbeat"]
+fn heartbeat() {
+    ic_cdk::setup();
+    ic_cdk::block_on(deliver_on_move_events());
+}
+
+async fn deliver_on_move_events() {
+    deliver_pending_events(|| unsafe { &mut TOKEN.as_mut().unwrap().on_move_listeners }).await;
+}
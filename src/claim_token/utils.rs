@@ -3,8 +3,8 @@ use std::collections::HashMap;
 use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 
 use union_utils::types::{
-    Account, OnMoveListener, OnMoveListenerError, OnMoveListenersInfo, TokenMoveEvent,
-    TokenMoveEventAndListeners,
+    Account, OnMoveListener, OnMoveListenerError, OnMoveListenersInfo, OnMoveListenersInfoV1,
+    TokenMoveEvent, TokenMoveEventLog,
 };
 
 /*
@@ -64,6 +64,7 @@ pub struct ClaimToken {
     pub total_supply: u64,
     pub info: ClaimTokenInfo,
     pub on_move_listeners: OnMoveListenersInfo,
+    pub event_log: TokenMoveEventLog,
     pub controllers: Controllers,
 }
 
@@ -81,7 +82,8 @@ impl ClaimToken {
         &mut self,
         to: Principal,
         caller: Principal,
-    ) -> Result<TokenMoveEventAndListeners, Error> {
+        timestamp: u64,
+    ) -> Result<TokenMoveEvent, Error> {
         check_controlled_op(self.controllers.issue_controller, caller)?;
 
         if self.has_claim(&to) {
@@ -91,14 +93,15 @@ impl ClaimToken {
         self.claims.insert(to, true);
         self.total_supply += 1;
 
-        Ok(self.create_event_and_find_listeners(Account::None, Account::Some(to), 1))
+        Ok(self.emit_event(Account::None, Account::Some(to), 1, timestamp))
     }
 
     pub fn revoke(
         &mut self,
         from: Principal,
         caller: Principal,
-    ) -> Result<TokenMoveEventAndListeners, Error> {
+        timestamp: u64,
+    ) -> Result<TokenMoveEvent, Error> {
         check_controlled_op(self.controllers.revoke_controller, caller)?;
 
         if !self.has_claim(&from) {
@@ -108,7 +111,7 @@ impl ClaimToken {
         self.claims.insert(from, false);
         self.total_supply -= 1;
 
-        Ok(self.create_event_and_find_listeners(Account::Some(from), Account::None, 1))
+        Ok(self.emit_event(Account::Some(from), Account::None, 1, timestamp))
     }
 
     pub fn subscribe_on_move(
@@ -135,6 +138,14 @@ impl ClaimToken {
             .map_err(Error::ListenerError)
     }
 
+    pub fn enable_on_move_listener(&mut self, id: u64, caller: Principal) -> Result<(), Error> {
+        check_controlled_op(self.controllers.on_move_controller, caller)?;
+
+        self.on_move_listeners
+            .enable_listener(id)
+            .map_err(Error::ListenerError)
+    }
+
     pub fn update_info(
         &mut self,
         new_info: ClaimTokenInfo,
@@ -203,18 +214,21 @@ impl ClaimToken {
         }
     }
 
-    fn create_event_and_find_listeners(
-        &self,
+    // the event is put into the outbox of every matching listener and delivered later
+    fn emit_event(
+        &mut self,
         from: Account,
         to: Account,
         qty: u64,
-    ) -> TokenMoveEventAndListeners {
-        let event = TokenMoveEvent { from, to, qty };
+        timestamp: u64,
+    ) -> TokenMoveEvent {
+        let event = self
+            .event_log
+            .emit(from, to, u128::from(qty), None, timestamp);
 
-        TokenMoveEventAndListeners {
-            event: event.clone(),
-            listeners: self.on_move_listeners.get_matching_listeners(&event),
-        }
+        self.on_move_listeners.enqueue_event(event.clone());
+
+        event
     }
 }
 
@@ -229,3 +243,118 @@ fn check_controlled_op(controller: Account, caller: Principal) -> Result<(), Err
 
     Ok(())
 }
+
+// claims and their info kept the V1 layout, V2 only adds the event log and the listener outboxes
+#[derive(CandidType, Deserialize)]
+pub enum VersionedClaimToken {
+    V1(ClaimTokenV1),
+    V2(ClaimToken),
+}
+
+impl VersionedClaimToken {
+    pub fn into_latest(self) -> ClaimToken {
+        match self {
+            VersionedClaimToken::V1(token) => token.into_v2(),
+            VersionedClaimToken::V2(token) => token,
+        }
+    }
+}
+
+// the V1 layout, frozen - there was no event log and listeners had no outboxes
+#[derive(CandidType, Deserialize)]
+pub struct ClaimTokenV1 {
+    pub claims: HashMap<Principal, bool>,
+    pub total_supply: u64,
+    pub info: ClaimTokenInfo,
+    pub on_move_listeners: OnMoveListenersInfoV1,
+    pub controllers: Controllers,
+}
+
+impl ClaimTokenV1 {
+    // the history before the upgrade wasn't logged, so the log starts empty
+    fn into_v2(self) -> ClaimToken {
+        ClaimToken {
+            claims: self.claims,
+            total_supply: self.total_supply,
+            info: self.info,
+            on_move_listeners: self.on_move_listeners.into_latest(),
+            event_log: TokenMoveEventLog::default(),
+            controllers: self.controllers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use union_utils::testing::{principal, stable_round_trip, v1_listeners};
+
+    use super::*;
+
+    #[test]
+    fn state_survives_stable_memory_round_trip() {
+        let holder = principal(1);
+        let revoked = principal(2);
+        let controller = principal(3);
+
+        let mut claims = HashMap::new();
+        claims.insert(holder, true);
+        claims.insert(revoked, false);
+
+        let token = ClaimToken {
+            claims: claims.clone(),
+            total_supply: 1,
+            info: ClaimTokenInfo {
+                name: String::from("Test"),
+            },
+            on_move_listeners: OnMoveListenersInfo::default(),
+            event_log: TokenMoveEventLog::default(),
+            controllers: Controllers::single(Some(controller)),
+        };
+
+        let restored = stable_round_trip(VersionedClaimToken::V2(token)).into_latest();
+
+        assert_eq!(restored.claims, claims);
+        assert_eq!(restored.total_supply, 1);
+        assert_eq!(restored.info.name, "Test");
+        assert!(restored.has_claim(&holder));
+        assert!(!restored.has_claim(&revoked));
+        assert_eq!(restored.controllers.revoke_controller, Some(controller));
+    }
+
+    #[test]
+    fn v1_state_is_migrated() {
+        let holder = principal(1);
+        let controller = principal(3);
+
+        let mut claims = HashMap::new();
+        claims.insert(holder, true);
+
+        let token = ClaimTokenV1 {
+            claims,
+            total_supply: 1,
+            info: ClaimTokenInfo {
+                name: String::from("Test"),
+            },
+            on_move_listeners: v1_listeners(4, None, Some(Some(holder)), controller),
+            controllers: Controllers::single(Some(controller)),
+        };
+
+        let mut restored = stable_round_trip(VersionedClaimToken::V1(token)).into_latest();
+
+        assert!(restored.has_claim(&holder));
+        assert_eq!(restored.event_log.next_seq(), 0);
+        assert_eq!(restored.on_move_listeners.id_counter, 5);
+
+        let other = principal(2);
+        restored.issue(other, controller, 10).unwrap();
+        restored.revoke(holder, controller, 20).unwrap();
+        restored.issue(holder, controller, 30).unwrap();
+
+        // the migrated listener only gets the events which match its filter
+        let deliveries = restored.on_move_listeners.take_pending_deliveries(0);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].listener_id, 4);
+        assert_eq!(deliveries[0].events.len(), 1);
+        assert_eq!(deliveries[0].events[0].seq, 2);
+    }
+}
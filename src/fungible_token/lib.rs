@@ -1,16 +1,21 @@
 use std::collections::HashMap;
 
-use futures::future::join_all;
+use ic_cdk::api::time;
 use ic_cdk::export::candid::Principal;
+use ic_cdk::storage::{stable_restore, stable_save};
 use ic_cdk::{caller, trap};
-use ic_cdk_macros::{init, query, update};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 
-use union_utils::fns::{log, send_events};
-use union_utils::types::{Account, OnMoveListener, OnMoveListenersInfo};
+use union_utils::fns::{deliver_pending_events, log};
+use union_utils::types::{
+    Account, ListenerBacklog, OnMoveListener, OnMoveListenersInfo, TokenMoveEvent,
+};
 
 use crate::utils::{
-    Controllers, Error, FungibleToken, FungibleTokenInfo, FungibleTokenInitPayload,
-    FungibleTokenTransferEntry,
+    Balance, BalanceSnapshot, BatchTransferError, Controllers, Error, FungibleToken,
+    FungibleTokenInfo, FungibleTokenInitPayload, FungibleTokenTransferEntry,
+    FungibleTokenTransferFromEntry, TransactionLog, TransactionPage, TransferDeduplicator,
+    VersionedFungibleToken,
 };
 
 mod utils;
@@ -27,6 +32,9 @@ fn init(payload: FungibleTokenInitPayload) {
 
     let mut token = FungibleToken {
         balances: HashMap::new(),
+        allowances: HashMap::new(),
+        transaction_log: TransactionLog::default(),
+        deduplicator: TransferDeduplicator::default(),
         total_supply: 0,
         on_move_listeners: OnMoveListenersInfo::default(),
         info: payload.info,
@@ -50,8 +58,31 @@ fn init(payload: FungibleTokenInitPayload) {
     }
 }
 
+#[pre_upgrade]
+fn pre_upgrade_hook() {
+    log("fungible_token.pre_upgrade()");
+
+    let token = unsafe { TOKEN.take().unwrap() };
+
+    stable_save((VersionedFungibleToken::V2(token),))
+        .expect("Unable to save the token to stable memory");
+}
+
+#[post_upgrade]
+fn post_upgrade_hook() {
+    log("fungible_token.post_upgrade()");
+
+    let (versioned,): (VersionedFungibleToken,) =
+        stable_restore().expect("Unable to restore the token from stable memory");
+
+    let mut token = versioned.into_latest();
+    token.on_move_listeners.reset_in_flight_deliveries();
+
+    unsafe { TOKEN = Some(token) }
+}
+
 #[query]
-fn balance_of(token_holder: Principal) -> u64 {
+fn balance_of(token_holder: Principal) -> Balance {
     log("fungible_token.balance_of()");
 
     let token = unsafe { TOKEN.as_ref().unwrap() };
@@ -59,8 +90,38 @@ fn balance_of(token_holder: Principal) -> u64 {
     token.balance_of(&token_holder)
 }
 
+// lets a listener, subscribed before the call, tell which of its events are already in the balance
+#[query]
+fn balance_snapshot_of(token_holder: Principal) -> BalanceSnapshot {
+    log("fungible_token.balance_snapshot_of()");
+
+    let token = unsafe { TOKEN.as_ref().unwrap() };
+
+    token.balance_snapshot_of(&token_holder)
+}
+
+#[query]
+fn get_transactions(offset: usize, limit: usize) -> TransactionPage {
+    log("fungible_token.get_transactions()");
+
+    let token = unsafe { TOKEN.as_ref().unwrap() };
+
+    token.transaction_log.get_transactions(offset, limit)
+}
+
 #[query]
-fn total_supply() -> u64 {
+fn get_account_transactions(account: Principal, offset: usize, limit: usize) -> TransactionPage {
+    log("fungible_token.get_account_transactions()");
+
+    let token = unsafe { TOKEN.as_ref().unwrap() };
+
+    token
+        .transaction_log
+        .get_account_transactions(&account, offset, limit)
+}
+
+#[query]
+fn total_supply() -> Balance {
     log("fungible_token.total_supply()");
 
     let token = unsafe { TOKEN.as_ref().unwrap() };
@@ -130,19 +191,12 @@ async fn mint(entries: Vec<FungibleTokenTransferEntry>) -> Vec<Result<(), Error>
 
     let results: Vec<_> = entries
         .into_iter()
-        .map(|entry| token.mint(entry.to, entry.qty, caller()))
-        .map(|res| async {
-            match res {
-                Ok(ev_n_list) => {
-                    send_events(ev_n_list).await;
-                    Ok(())
-                }
-                Err(e) => Err(e),
-            }
-        })
+        .map(|entry| token.mint(entry, caller(), time()).map(|_| ()))
         .collect();
 
-    join_all(results).await
+    deliver_on_move_events().await;
+
+    results
 }
 
 #[update]
@@ -153,29 +207,68 @@ async fn send(entries: Vec<FungibleTokenTransferEntry>) -> Vec<Result<(), Error>
 
     let results: Vec<_> = entries
         .into_iter()
-        .map(|entry| token.send(caller(), entry.to, entry.qty))
-        .map(|res| async {
-            match res {
-                Ok(ev_n_list) => {
-                    send_events(ev_n_list).await;
-                    Ok(())
-                }
-                Err(e) => Err(e),
-            }
-        })
+        .map(|entry| token.send(caller(), entry, time()).map(|_| ()))
         .collect();
 
-    join_all(results).await
+    deliver_on_move_events().await;
+
+    results
 }
 
 #[update]
-async fn burn(quantity: u64) -> Result<(), Error> {
+async fn send_batch(entries: Vec<FungibleTokenTransferEntry>) -> Result<(), BatchTransferError> {
+    log("fungible_token.send_batch()");
+
+    let token = unsafe { TOKEN.as_mut().unwrap() };
+
+    token.send_batch(caller(), entries, time())?;
+    deliver_on_move_events().await;
+
+    Ok(())
+}
+
+#[update]
+fn approve(spender: Principal, qty: Balance, expires_at: Option<u64>) -> Result<(), Error> {
+    log("fungible_token.approve()");
+
+    let token = unsafe { TOKEN.as_mut().unwrap() };
+
+    token.approve(caller(), spender, qty, expires_at)
+}
+
+#[query]
+fn allowance(owner: Principal, spender: Principal) -> Balance {
+    log("fungible_token.allowance()");
+
+    let token = unsafe { TOKEN.as_ref().unwrap() };
+
+    token.allowance(&owner, &spender, time())
+}
+
+#[update]
+async fn transfer_from(entries: Vec<FungibleTokenTransferFromEntry>) -> Vec<Result<(), Error>> {
+    log("fungible_token.transfer_from()");
+
+    let token = unsafe { TOKEN.as_mut().unwrap() };
+
+    let results: Vec<_> = entries
+        .into_iter()
+        .map(|entry| token.transfer_from(caller(), entry, time()).map(|_| ()))
+        .collect();
+
+    deliver_on_move_events().await;
+
+    results
+}
+
+#[update]
+async fn burn(quantity: Balance) -> Result<(), Error> {
     log("fungible_token.burn()");
 
     let token = unsafe { TOKEN.as_mut().unwrap() };
 
-    let ev_and_listeners = token.burn(caller(), quantity)?;
-    send_events(ev_and_listeners).await;
+    token.burn(caller(), quantity, time())?;
+    deliver_on_move_events().await;
 
     Ok(())
 }
@@ -203,3 +296,45 @@ fn unsubscribe_on_move(listener_ids: Vec<u64>) -> Vec<Result<OnMoveListener, Err
         .map(|listener_id| token.unsubscribe_on_move(listener_id, caller()))
         .collect()
 }
+
+#[query]
+fn get_events(from_seq: u64, limit: u64) -> Vec<TokenMoveEvent> {
+    log("fungible_token.get_events()");
+
+    let token = unsafe { TOKEN.as_ref().unwrap() };
+
+    token.transaction_log.get_events(from_seq, limit)
+}
+
+#[query]
+fn on_move_backlog() -> Vec<ListenerBacklog> {
+    log("fungible_token.on_move_backlog()");
+
+    let token = unsafe { TOKEN.as_ref().unwrap() };
+
+    token.on_move_listeners.get_backlog()
+}
+
+#[update]
+async fn enable_on_move_listener(listener_id: u64) -> Result<(), Error> {
+    log("fungible_token.enable_on_move_listener()");
+
+    let token = unsafe { TOKEN.as_mut().unwrap() };
+    token.enable_on_move_listener(listener_id, caller())?;
+
+    deliver_on_move_events().await;
+
+    Ok(())
+}
+
+// a failed delivery becomes due again while nobody may be moving the token, so the heartbeat
+// takes care of the retries; exported by hand, since ic-cdk-macros has no heartbeat attribute
+#[export_name = "canister_heartbeat"]
+fn heartbeat() {
+    ic_cdk::setup();
+    ic_cdk::block_on(deliver_on_move_events());
+}
+
+async fn deliver_on_move_events() {
+    deliver_pending_events(|| unsafe { &mut TOKEN.as_mut().unwrap().on_move_listeners }).await;
+}
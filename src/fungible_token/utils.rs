@@ -1,12 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 
 use union_utils::types::{
-    Account, OnMoveListener, OnMoveListenerError, OnMoveListenersInfo, TokenMoveEvent,
-    TokenMoveEventAndListeners,
+    Account, OnMoveListener, OnMoveListenerError, OnMoveListenersInfo, OnMoveListenersInfoV1,
+    TokenMoveEvent, MAX_EVENTS_PAGE, MAX_EVENT_LOG_LENGTH,
 };
 
+// amount of tokens, candid nat - u64 isn't enough for the supply of a token with 18 decimals
+pub type Balance = u128;
+
 /*
   type Controllers = record {
     mint_controller : Account;
@@ -34,13 +37,18 @@ impl Controllers {
 /*
   type FungibleTokenTransferEntry {
     to : principal;
-    qty : nat64;
+    qty : nat;
+    memo : opt blob;
+    created_at : opt nat64;
   }
  */
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct FungibleTokenTransferEntry {
     pub to: Principal,
-    pub qty: u64
+    pub qty: Balance,
+    pub memo: Option<Vec<u8>>,
+    // when set, the same transfer is rejected as a duplicate within the deduplication window
+    pub created_at: Option<u64>,
 }
 
 /*
@@ -62,6 +70,7 @@ pub struct FungibleTokenInitPayload {
    name : Text;
    symbol : Text;
    decimals : nat8;
+   max_supply : opt nat;
  }
 */
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -69,12 +78,324 @@ pub struct FungibleTokenInfo {
     pub name: String,
     pub symbol: String,
     pub decimals: u8,
+    pub max_supply: Option<Balance>,
+}
+
+/*
+ type BalanceSnapshot = record {
+   balance : nat;
+   next_seq : nat64;
+ }
+*/
+// the balance includes the effect of every event with a seq below next_seq
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BalanceSnapshot {
+    pub balance: Balance,
+    pub next_seq: u64,
+}
+
+/*
+ type FungibleTokenTransferFromEntry = record {
+   from : principal;
+   to : principal;
+   qty : nat;
+   memo : opt blob;
+   created_at : opt nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FungibleTokenTransferFromEntry {
+    pub from: Principal,
+    pub to: Principal,
+    pub qty: Balance,
+    pub memo: Option<Vec<u8>>,
+    pub created_at: Option<u64>,
+}
+
+/*
+ type Allowance = record {
+   qty : nat;
+   expires_at : opt nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Allowance {
+    pub qty: Balance,
+    pub expires_at: Option<u64>,
+}
+
+impl Allowance {
+    pub fn is_expired(&self, timestamp: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= timestamp)
+    }
+}
+
+/*
+ type TransactionKind = variant {
+   Mint;
+   Send;
+   TransferFrom : principal;
+   Burn;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransactionKind {
+    Mint,
+    Send,
+    // the spender who moved the tokens on behalf of the owner
+    TransferFrom(Principal),
+    Burn,
+}
+
+/*
+ type Transaction = record {
+   id : nat64;
+   kind : TransactionKind;
+   from : Account;
+   to : Account;
+   qty : nat;
+   timestamp : nat64;
+   memo : opt blob;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Transaction {
+    pub id: u64,
+    pub kind: TransactionKind,
+    pub from: Account,
+    pub to: Account,
+    pub qty: Balance,
+    pub timestamp: u64,
+    pub memo: Option<Vec<u8>>,
+}
+
+impl Transaction {
+    // every transaction moves tokens, so the transaction log doubles as the event log
+    pub fn to_event(&self) -> TokenMoveEvent {
+        TokenMoveEvent {
+            seq: self.id,
+            timestamp: self.timestamp,
+            from: self.from,
+            to: self.to,
+            qty: self.qty,
+            memo: self.memo.clone(),
+            prev_seq: self.id.checked_sub(1),
+        }
+    }
+}
+
+/*
+ type TransactionPage = record {
+   entries : vec Transaction;
+   total : nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransactionPage {
+    pub entries: Vec<Transaction>,
+    pub total: usize,
+}
+
+// transactions are never changed, only the oldest ones are pruned past MAX_EVENT_LOG_LENGTH,
+// so the id of a transaction is its position among all of them
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct TransactionLog {
+    // id of the oldest transaction which is still kept
+    pub first_id: u64,
+    pub transactions: VecDeque<Transaction>,
+    pub account_index: HashMap<Principal, VecDeque<u64>>,
+}
+
+impl TransactionLog {
+    pub fn append(
+        &mut self,
+        kind: TransactionKind,
+        from: Account,
+        to: Account,
+        qty: Balance,
+        timestamp: u64,
+        memo: Option<Vec<u8>>,
+    ) -> u64 {
+        let id = self.next_id();
+
+        for account in from.iter().chain(to.iter()) {
+            let ids = self.account_index.entry(*account).or_default();
+
+            // self-transfers are indexed once
+            if ids.back() != Some(&id) {
+                ids.push_back(id);
+            }
+        }
+
+        self.transactions.push_back(Transaction {
+            id,
+            kind,
+            from,
+            to,
+            qty,
+            timestamp,
+            memo,
+        });
+
+        if self.transactions.len() > MAX_EVENT_LOG_LENGTH {
+            self.prune_oldest();
+        }
+
+        id
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.first_id + self.transactions.len() as u64
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Transaction> {
+        self.transactions
+            .get(id.checked_sub(self.first_id)? as usize)
+    }
+
+    fn prune_oldest(&mut self) {
+        let pruned = match self.transactions.pop_front() {
+            Some(tx) => tx,
+            None => return,
+        };
+        self.first_id += 1;
+
+        for account in pruned.from.iter().chain(pruned.to.iter()) {
+            if let Some(ids) = self.account_index.get_mut(account) {
+                // the index is sorted, so the pruned id can only be the first one
+                if ids.front() == Some(&pruned.id) {
+                    ids.pop_front();
+                }
+
+                if ids.is_empty() {
+                    self.account_index.remove(account);
+                }
+            }
+        }
+    }
+
+    // the same paging as TokenMoveEventLog::get_events()
+    pub fn get_events(&self, from_seq: u64, limit: u64) -> Vec<TokenMoveEvent> {
+        self.transactions
+            .iter()
+            .skip(from_seq.saturating_sub(self.first_id) as usize)
+            .take(limit.min(MAX_EVENTS_PAGE) as usize)
+            .map(Transaction::to_event)
+            .collect()
+    }
+
+    pub fn get_transactions(&self, offset: usize, limit: usize) -> TransactionPage {
+        TransactionPage {
+            entries: self
+                .transactions
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+            total: self.transactions.len(),
+        }
+    }
+
+    pub fn get_account_transactions(
+        &self,
+        account: &Principal,
+        offset: usize,
+        limit: usize,
+    ) -> TransactionPage {
+        let ids = match self.account_index.get(account) {
+            Some(ids) => ids,
+            None => {
+                return TransactionPage {
+                    entries: Vec::new(),
+                    total: 0,
+                }
+            }
+        };
+
+        TransactionPage {
+            entries: ids
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .filter_map(|id| self.get(*id).cloned())
+                .collect(),
+            total: ids.len(),
+        }
+    }
+}
+
+pub const TRANSACTION_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const PERMITTED_DRIFT: u64 = 60 * 1_000_000_000;
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq, CandidType, Deserialize)]
+pub struct TransferKey {
+    pub caller: Principal,
+    pub from: Account,
+    pub to: Account,
+    pub qty: Balance,
+    pub memo: Option<Vec<u8>>,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct TransferDeduplicator {
+    pub index: HashMap<TransferKey, u64>,
+    // keys in the order they were registered in, paired with the registration time
+    pub queue: VecDeque<(u64, TransferKey)>,
+}
+
+impl TransferDeduplicator {
+    pub fn check(&mut self, key: &TransferKey, timestamp: u64) -> Result<(), Error> {
+        self.prune(timestamp);
+
+        if key.created_at.saturating_add(TRANSACTION_WINDOW) < timestamp {
+            return Err(Error::TooOld);
+        }
+
+        if key.created_at > timestamp.saturating_add(PERMITTED_DRIFT) {
+            return Err(Error::CreatedInFuture);
+        }
+
+        if let Some(tx_id) = self.index.get(key) {
+            return Err(Error::Duplicate { tx_id: *tx_id });
+        }
+
+        Ok(())
+    }
+
+    pub fn register(&mut self, key: TransferKey, tx_id: u64, timestamp: u64) {
+        self.queue.push_back((timestamp, key.clone()));
+        self.index.insert(key, tx_id);
+    }
+
+    // a key registered at T was created before T + drift, so after T + drift + window
+    // the same transfer is rejected as too old and the key is no longer needed
+    fn prune(&mut self, timestamp: u64) {
+        while let Some((registered_at, _)) = self.queue.front() {
+            let expires_at = registered_at
+                .saturating_add(TRANSACTION_WINDOW)
+                .saturating_add(PERMITTED_DRIFT);
+
+            if expires_at >= timestamp {
+                break;
+            }
+
+            if let Some((_, key)) = self.queue.pop_front() {
+                self.index.remove(&key);
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct FungibleToken {
-    pub balances: HashMap<Principal, u64>,
-    pub total_supply: u64,
+    pub balances: HashMap<Principal, Balance>,
+    // owner -> spender -> allowance
+    pub allowances: HashMap<Principal, HashMap<Principal, Allowance>>,
+    pub transaction_log: TransactionLog,
+    pub deduplicator: TransferDeduplicator,
+    pub total_supply: Balance,
     pub info: FungibleTokenInfo,
     pub on_move_listeners: OnMoveListenersInfo,
     pub controllers: Controllers,
@@ -83,62 +404,337 @@ pub struct FungibleToken {
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum Error {
     InsufficientBalance,
+    InsufficientAllowance,
+    AllowanceExpired,
+    TooOld,
+    CreatedInFuture,
+    Duplicate { tx_id: u64 },
+    // the same transfer is already an earlier entry of the batch
+    DuplicateInBatch { index: usize },
+    SupplyOverflow,
+    MaxSupplyExceeded,
     AccessDenied,
     ForbiddenOperation,
     ListenerError(OnMoveListenerError),
 }
 
+/*
+ type BatchTransferError = record {
+   index : opt nat64;
+   error : Error;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BatchTransferError {
+    // None when the batch as a whole is invalid, e.g. exceeds the sender's balance
+    pub index: Option<usize>,
+    pub error: Error,
+}
+
 impl FungibleToken {
     pub fn mint(
         &mut self,
-        to: Principal,
-        qty: u64,
+        entry: FungibleTokenTransferEntry,
         caller: Principal,
-    ) -> Result<TokenMoveEventAndListeners, Error> {
+        timestamp: u64,
+    ) -> Result<TokenMoveEvent, Error> {
         check_controlled_op(self.controllers.mint_controller, caller)?;
 
-        let prev_balance = self.balance_of(&to);
+        let key = self.check_duplicate(
+            caller,
+            Account::None,
+            Account::Some(entry.to),
+            &entry,
+            timestamp,
+        )?;
+
+        let total_supply = self
+            .total_supply
+            .checked_add(entry.qty)
+            .ok_or(Error::SupplyOverflow)?;
 
-        self.total_supply += qty;
-        self.balances.insert(to, prev_balance + qty);
+        if matches!(self.info.max_supply, Some(max_supply) if total_supply > max_supply) {
+            return Err(Error::MaxSupplyExceeded);
+        }
+
+        // can't overflow, since every balance is a part of the total supply
+        let balance = self.balance_of(&entry.to) + entry.qty;
+
+        self.total_supply = total_supply;
+        self.balances.insert(entry.to, balance);
 
-        Ok(self.create_event_and_find_listeners(Account::None, Account::Some(to), qty))
+        self.commit_transfer(TransactionKind::Mint, Account::None, entry, key, timestamp)
     }
 
     pub fn send(
         &mut self,
         from: Principal,
-        to: Principal,
-        qty: u64,
-    ) -> Result<TokenMoveEventAndListeners, Error> {
-        let from_prev_balance = self.balance_of(&from);
-        let to_prev_balance = self.balance_of(&to);
+        entry: FungibleTokenTransferEntry,
+        timestamp: u64,
+    ) -> Result<TokenMoveEvent, Error> {
+        let key = self.check_duplicate(
+            from,
+            Account::Some(from),
+            Account::Some(entry.to),
+            &entry,
+            timestamp,
+        )?;
 
-        if from_prev_balance < qty {
-            return Err(Error::InsufficientBalance);
+        self.move_balance(from, entry.to, entry.qty)?;
+
+        self.commit_transfer(
+            TransactionKind::Send,
+            Account::Some(from),
+            entry,
+            key,
+            timestamp,
+        )
+    }
+
+    // either every entry is applied or none of them,
+    // so the events are put into the outbox only when the whole batch is committed
+    pub fn send_batch(
+        &mut self,
+        from: Principal,
+        entries: Vec<FungibleTokenTransferEntry>,
+        timestamp: u64,
+    ) -> Result<(), BatchTransferError> {
+        let batch_error = |index: Option<usize>, error: Error| BatchTransferError { index, error };
+
+        let total = entries
+            .iter()
+            .try_fold(0 as Balance, |sum, entry| sum.checked_add(entry.qty))
+            .ok_or_else(|| batch_error(None, Error::SupplyOverflow))?;
+
+        if self.balance_of(&from) < total {
+            return Err(batch_error(None, Error::InsufficientBalance));
         }
 
-        self.balances.insert(from, from_prev_balance - qty);
-        self.balances.insert(to, to_prev_balance + qty);
+        let mut keys = Vec::new();
+        let mut batch_keys = HashMap::new();
 
-        Ok(self.create_event_and_find_listeners(
-            Account::Some(from),
-            Account::Some(to),
-            qty,
-        ))
+        for (index, entry) in entries.iter().enumerate() {
+            let key = self
+                .check_duplicate(
+                    from,
+                    Account::Some(from),
+                    Account::Some(entry.to),
+                    entry,
+                    timestamp,
+                )
+                .map_err(|e| batch_error(Some(index), e))?;
+
+            // duplicates inside the batch itself are not registered yet
+            if let Some(key) = &key {
+                if let Some(earlier) = batch_keys.insert(key.clone(), index) {
+                    return Err(batch_error(
+                        Some(index),
+                        Error::DuplicateInBatch { index: earlier },
+                    ));
+                }
+            }
+
+            keys.push(key);
+        }
+
+        // the total is already checked, so none of the moves can fail from now on
+        for (index, (entry, key)) in entries.into_iter().zip(keys).enumerate() {
+            self.move_balance(from, entry.to, entry.qty)
+                .map_err(|e| batch_error(Some(index), e))?;
+
+            self.commit_transfer(
+                TransactionKind::Send,
+                Account::Some(from),
+                entry,
+                key,
+                timestamp,
+            )
+            .map_err(|e| batch_error(Some(index), e))?;
+        }
+
+        Ok(())
+    }
+
+    fn check_duplicate(
+        &mut self,
+        caller: Principal,
+        from: Account,
+        to: Account,
+        entry: &FungibleTokenTransferEntry,
+        timestamp: u64,
+    ) -> Result<Option<TransferKey>, Error> {
+        let key = entry.created_at.map(|created_at| TransferKey {
+            caller,
+            from,
+            to,
+            qty: entry.qty,
+            memo: entry.memo.clone(),
+            created_at,
+        });
+
+        if let Some(key) = &key {
+            self.deduplicator.check(key, timestamp)?;
+        }
+
+        Ok(key)
+    }
+
+    // logs the transfer which balances are already updated
+    fn commit_transfer(
+        &mut self,
+        kind: TransactionKind,
+        from: Account,
+        entry: FungibleTokenTransferEntry,
+        key: Option<TransferKey>,
+        timestamp: u64,
+    ) -> Result<TokenMoveEvent, Error> {
+        let to = Account::Some(entry.to);
+
+        let tx_id = self
+            .transaction_log
+            .append(kind, from, to, entry.qty, timestamp, entry.memo);
+
+        if let Some(key) = key {
+            self.deduplicator.register(key, tx_id, timestamp);
+        }
+
+        Ok(self.emit_event(tx_id))
     }
 
-    pub fn burn(&mut self, from: Principal, qty: u64) -> Result<TokenMoveEventAndListeners, Error> {
-        let prev_balance = self.balance_of(&from);
+    fn move_balance(&mut self, from: Principal, to: Principal, qty: Balance) -> Result<(), Error> {
+        let from_balance = self
+            .balance_of(&from)
+            .checked_sub(qty)
+            .ok_or(Error::InsufficientBalance)?;
 
-        if prev_balance < qty {
-            return Err(Error::InsufficientBalance);
+        // sending to oneself doesn't change the balance
+        if from == to {
+            return Ok(());
         }
 
+        let to_balance = self
+            .balance_of(&to)
+            .checked_add(qty)
+            .ok_or(Error::SupplyOverflow)?;
+
+        // both balances are checked, so nothing is written if either check fails
+        self.balances.insert(from, from_balance);
+        self.balances.insert(to, to_balance);
+
+        Ok(())
+    }
+
+    // overwrites the previous allowance, zero quantity revokes it
+    pub fn approve(
+        &mut self,
+        owner: Principal,
+        spender: Principal,
+        qty: Balance,
+        expires_at: Option<u64>,
+    ) -> Result<(), Error> {
+        if owner == spender {
+            return Err(Error::ForbiddenOperation);
+        }
+
+        let owner_allowances = self.allowances.entry(owner).or_default();
+
+        if qty == 0 {
+            owner_allowances.remove(&spender);
+        } else {
+            owner_allowances.insert(spender, Allowance { qty, expires_at });
+        }
+
+        if owner_allowances.is_empty() {
+            self.allowances.remove(&owner);
+        }
+
+        Ok(())
+    }
+
+    pub fn allowance(&self, owner: &Principal, spender: &Principal, timestamp: u64) -> Balance {
+        match self.allowances.get(owner).and_then(|a| a.get(spender)) {
+            Some(allowance) if !allowance.is_expired(timestamp) => allowance.qty,
+            _ => 0,
+        }
+    }
+
+    pub fn transfer_from(
+        &mut self,
+        spender: Principal,
+        entry: FungibleTokenTransferFromEntry,
+        timestamp: u64,
+    ) -> Result<TokenMoveEvent, Error> {
+        let from = entry.from;
+        let qty = entry.qty;
+        let entry = FungibleTokenTransferEntry {
+            to: entry.to,
+            qty: entry.qty,
+            memo: entry.memo,
+            created_at: entry.created_at,
+        };
+
+        let key = self.check_duplicate(
+            spender,
+            Account::Some(from),
+            Account::Some(entry.to),
+            &entry,
+            timestamp,
+        )?;
+
+        let allowance = self
+            .allowances
+            .get(&from)
+            .and_then(|a| a.get(&spender))
+            .ok_or(Error::InsufficientAllowance)?;
+
+        if allowance.is_expired(timestamp) {
+            return Err(Error::AllowanceExpired);
+        }
+
+        if allowance.qty < qty {
+            return Err(Error::InsufficientAllowance);
+        }
+
+        let rest = allowance.qty - qty;
+        let expires_at = allowance.expires_at;
+
+        self.move_balance(from, entry.to, qty)?;
+        self.approve(from, spender, rest, expires_at)?;
+
+        self.commit_transfer(
+            TransactionKind::TransferFrom(spender),
+            Account::Some(from),
+            entry,
+            key,
+            timestamp,
+        )
+    }
+
+    pub fn burn(
+        &mut self,
+        from: Principal,
+        qty: Balance,
+        timestamp: u64,
+    ) -> Result<TokenMoveEvent, Error> {
+        let balance = self
+            .balance_of(&from)
+            .checked_sub(qty)
+            .ok_or(Error::InsufficientBalance)?;
+
+        // can't underflow, since the balance is a part of the total supply
         self.total_supply -= qty;
-        self.balances.insert(from, prev_balance - qty);
+        self.balances.insert(from, balance);
 
-        Ok(self.create_event_and_find_listeners(Account::Some(from), Account::None, qty))
+        let tx_id = self.transaction_log.append(
+            TransactionKind::Burn,
+            Account::Some(from),
+            Account::None,
+            qty,
+            timestamp,
+            None,
+        );
+
+        Ok(self.emit_event(tx_id))
     }
 
     pub fn subscribe_on_move(
@@ -165,6 +761,14 @@ impl FungibleToken {
             .map_err(Error::ListenerError)
     }
 
+    pub fn enable_on_move_listener(&mut self, id: u64, caller: Principal) -> Result<(), Error> {
+        check_controlled_op(self.controllers.on_move_controller, caller)?;
+
+        self.on_move_listeners
+            .enable_listener(id)
+            .map_err(Error::ListenerError)
+    }
+
     pub fn update_info(
         &mut self,
         new_info: FungibleTokenInfo,
@@ -172,6 +776,10 @@ impl FungibleToken {
     ) -> Result<FungibleTokenInfo, Error> {
         check_controlled_op(self.controllers.info_controller, caller)?;
 
+        if matches!(new_info.max_supply, Some(max_supply) if max_supply < self.total_supply) {
+            return Err(Error::MaxSupplyExceeded);
+        }
+
         let old_info = self.info.clone();
         self.info = new_info;
 
@@ -214,26 +822,33 @@ impl FungibleToken {
         Ok(())
     }
 
-    pub fn balance_of(&self, token_holder: &Principal) -> u64 {
+    pub fn balance_of(&self, token_holder: &Principal) -> Balance {
         match self.balances.get(&token_holder) {
             None => 0,
             Some(b) => *b,
         }
     }
 
-    fn create_event_and_find_listeners(
-        &self,
-        from: Account,
-        to: Account,
-        qty: u64,
-    ) -> TokenMoveEventAndListeners {
-        let event = TokenMoveEvent { from, to, qty };
-
-        TokenMoveEventAndListeners {
-            event: event.clone(),
-            listeners: self.on_move_listeners.get_matching_listeners(&event),
+    pub fn balance_snapshot_of(&self, token_holder: &Principal) -> BalanceSnapshot {
+        BalanceSnapshot {
+            balance: self.balance_of(token_holder),
+            next_seq: self.transaction_log.next_id(),
         }
     }
+
+    // the event of the just logged transaction is put into the outbox of every matching listener
+    // and delivered later
+    fn emit_event(&mut self, tx_id: u64) -> TokenMoveEvent {
+        let event = self
+            .transaction_log
+            .get(tx_id)
+            .expect("The transaction is just logged")
+            .to_event();
+
+        self.on_move_listeners.enqueue_event(event.clone());
+
+        event
+    }
 }
 
 fn check_controlled_op(controller: Account, caller: Principal) -> Result<(), Error> {
@@ -247,3 +862,567 @@ fn check_controlled_op(controller: Account, caller: Principal) -> Result<(), Err
 
     Ok(())
 }
+
+// a variant per stored layout of the token, pre_upgrade always saves the latest one;
+// only lives for the duration of an upgrade, so the size of the V1 variant doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(CandidType, Deserialize)]
+pub enum VersionedFungibleToken {
+    V1(FungibleTokenV1),
+    V2(FungibleToken),
+}
+
+impl VersionedFungibleToken {
+    pub fn into_latest(self) -> FungibleToken {
+        match self {
+            VersionedFungibleToken::V1(token) => token.into_v2(),
+            VersionedFungibleToken::V2(token) => token,
+        }
+    }
+}
+
+// the V1 layout, frozen - there were no allowances, no transaction log and no deduplication
+#[derive(CandidType, Deserialize)]
+pub struct FungibleTokenV1 {
+    pub balances: HashMap<Principal, u64>,
+    pub total_supply: u64,
+    pub info: FungibleTokenInfoV1,
+    pub on_move_listeners: OnMoveListenersInfoV1,
+    pub controllers: Controllers,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct FungibleTokenInfoV1 {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+impl FungibleTokenV1 {
+    // the history before the upgrade wasn't logged, so the log starts empty
+    fn into_v2(self) -> FungibleToken {
+        let balances = self
+            .balances
+            .into_iter()
+            .map(|(holder, balance)| (holder, Balance::from(balance)))
+            .collect();
+
+        FungibleToken {
+            balances,
+            allowances: HashMap::new(),
+            transaction_log: TransactionLog::default(),
+            deduplicator: TransferDeduplicator::default(),
+            total_supply: Balance::from(self.total_supply),
+            // the supply wasn't capped before
+            info: FungibleTokenInfo {
+                name: self.info.name,
+                symbol: self.info.symbol,
+                decimals: self.info.decimals,
+                max_supply: None,
+            },
+            on_move_listeners: self.on_move_listeners.into_latest(),
+            controllers: self.controllers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use union_utils::testing::{
+        any_move, listener, move_event, principal, stable_round_trip, v1_listeners,
+    };
+
+    use super::*;
+
+    fn test_token(controller: Principal) -> FungibleToken {
+        FungibleToken {
+            balances: HashMap::new(),
+            allowances: HashMap::new(),
+            transaction_log: TransactionLog::default(),
+            deduplicator: TransferDeduplicator::default(),
+            total_supply: 0,
+            info: FungibleTokenInfo {
+                name: String::from("Test"),
+                symbol: String::from("TST"),
+                decimals: 8,
+                max_supply: None,
+            },
+            on_move_listeners: OnMoveListenersInfo::default(),
+            controllers: Controllers::single(Some(controller)),
+        }
+    }
+
+    fn transfer(to: Principal, qty: Balance) -> FungibleTokenTransferEntry {
+        FungibleTokenTransferEntry {
+            to,
+            qty,
+            memo: None,
+            created_at: None,
+        }
+    }
+
+    fn transfer_from(
+        from: Principal,
+        to: Principal,
+        qty: Balance,
+    ) -> FungibleTokenTransferFromEntry {
+        FungibleTokenTransferFromEntry {
+            from,
+            to,
+            qty,
+            memo: None,
+            created_at: None,
+        }
+    }
+
+    #[test]
+    fn state_survives_stable_memory_round_trip() {
+        let holder = principal(1);
+        let controller = principal(2);
+
+        let mut to_holder = any_move();
+        to_holder.to = Some(vec![Some(holder)]);
+
+        let mut on_move_listeners = OnMoveListenersInfo::default();
+        on_move_listeners
+            .add_listener(listener(to_holder, controller, "on_move"))
+            .unwrap();
+
+        let mut balances = HashMap::new();
+        balances.insert(holder, 100);
+
+        let token = FungibleToken {
+            balances: balances.clone(),
+            allowances: HashMap::new(),
+            transaction_log: TransactionLog::default(),
+            deduplicator: TransferDeduplicator::default(),
+            total_supply: 100,
+            info: FungibleTokenInfo {
+                name: String::from("Test"),
+                symbol: String::from("TST"),
+                decimals: 8,
+                max_supply: None,
+            },
+            on_move_listeners,
+            controllers: Controllers::single(Some(controller)),
+        };
+
+        let restored = stable_round_trip(VersionedFungibleToken::V2(token)).into_latest();
+
+        assert_eq!(restored.balances, balances);
+        assert_eq!(restored.total_supply, 100);
+        assert_eq!(restored.info.symbol, "TST");
+        assert_eq!(restored.info.decimals, 8);
+        assert_eq!(restored.on_move_listeners.id_counter, 1);
+        assert_eq!(
+            restored
+                .on_move_listeners
+                .get_matching_listeners(&move_event(0, None, Some(holder), 1))
+                .len(),
+            1
+        );
+        assert_eq!(restored.controllers.mint_controller, Some(controller));
+    }
+
+    #[test]
+    fn v1_state_is_migrated() {
+        let holder = principal(1);
+        let controller = principal(2);
+
+        let mut balances = HashMap::new();
+        balances.insert(holder, 100);
+
+        let token = FungibleTokenV1 {
+            balances,
+            total_supply: 100,
+            info: FungibleTokenInfoV1 {
+                name: String::from("Test"),
+                symbol: String::from("TST"),
+                decimals: 8,
+            },
+            on_move_listeners: v1_listeners(4, Some(Some(holder)), None, controller),
+            controllers: Controllers::single(Some(controller)),
+        };
+
+        let mut restored = stable_round_trip(VersionedFungibleToken::V1(token)).into_latest();
+
+        assert_eq!(restored.balance_of(&holder), 100);
+        assert_eq!(restored.total_supply, 100);
+        assert_eq!(restored.info.max_supply, None);
+        assert!(restored.allowances.is_empty());
+        assert_eq!(restored.transaction_log.next_id(), 0);
+        assert_eq!(restored.on_move_listeners.id_counter, 5);
+
+        restored.mint(transfer(holder, 10), controller, 0).unwrap();
+        restored.send(holder, transfer(controller, 30), 0).unwrap();
+
+        // the migrated listener only gets the events which match its filter
+        let deliveries = restored.on_move_listeners.take_pending_deliveries(0);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].listener_id, 4);
+        assert_eq!(deliveries[0].events.len(), 1);
+        assert_eq!(deliveries[0].events[0].seq, 1);
+        assert_eq!(restored.balance_of(&holder), 80);
+    }
+
+    #[test]
+    fn transfer_from_spends_the_allowance() {
+        let owner = principal(1);
+        let spender = principal(2);
+        let receiver = principal(3);
+
+        let mut token = test_token(owner);
+        token.mint(transfer(owner, 100), owner, 0).unwrap();
+        token.approve(owner, spender, 50, None).unwrap();
+
+        token
+            .transfer_from(spender, transfer_from(owner, receiver, 20), 0)
+            .unwrap();
+        assert_eq!(token.allowance(&owner, &spender, 0), 30);
+        assert_eq!(token.balance_of(&owner), 80);
+        assert_eq!(token.balance_of(&receiver), 20);
+
+        assert!(matches!(
+            token.transfer_from(spender, transfer_from(owner, receiver, 31), 0),
+            Err(Error::InsufficientAllowance)
+        ));
+        assert_eq!(token.balance_of(&owner), 80);
+
+        // spending the rest revokes the allowance
+        token
+            .transfer_from(spender, transfer_from(owner, receiver, 30), 0)
+            .unwrap();
+        assert_eq!(token.allowance(&owner, &spender, 0), 0);
+        assert!(token.allowances.is_empty());
+        assert!(matches!(
+            token.transfer_from(spender, transfer_from(owner, receiver, 1), 0),
+            Err(Error::InsufficientAllowance)
+        ));
+
+        let log = token.transaction_log.get_transactions(0, 10);
+        assert!(matches!(log.entries[2].kind, TransactionKind::TransferFrom(s) if s == spender));
+    }
+
+    #[test]
+    fn expired_allowances_cannot_be_spent() {
+        let owner = principal(1);
+        let spender = principal(2);
+
+        let mut token = test_token(owner);
+        token.mint(transfer(owner, 100), owner, 0).unwrap();
+        token.approve(owner, spender, 50, Some(10)).unwrap();
+
+        assert_eq!(token.allowance(&owner, &spender, 9), 50);
+        assert_eq!(token.allowance(&owner, &spender, 10), 0);
+
+        token
+            .transfer_from(spender, transfer_from(owner, spender, 10), 9)
+            .unwrap();
+        assert!(matches!(
+            token.transfer_from(spender, transfer_from(owner, spender, 10), 10),
+            Err(Error::AllowanceExpired)
+        ));
+        assert_eq!(token.balance_of(&spender), 10);
+
+        // the expiration is kept as long as the rest isn't spent
+        assert_eq!(token.allowances[&owner][&spender].expires_at, Some(10));
+
+        assert!(matches!(
+            token.approve(owner, owner, 10, None),
+            Err(Error::ForbiddenOperation)
+        ));
+    }
+
+    #[test]
+    fn transactions_are_paginated_per_account() {
+        let alice = principal(1);
+        let bob = principal(2);
+        let stranger = principal(3);
+
+        let mut token = test_token(alice);
+        token.mint(transfer(alice, 100), alice, 0).unwrap();
+        for timestamp in 1..=4 {
+            let qty = Balance::from(timestamp);
+            token.send(alice, transfer(bob, qty), timestamp).unwrap();
+        }
+        token.send(alice, transfer(alice, 5), 5).unwrap();
+        token.burn(bob, 1, 6).unwrap();
+
+        let log = &token.transaction_log;
+
+        let page = log.get_transactions(2, 3);
+        assert_eq!(page.total, 7);
+        let ids: Vec<u64> = page.entries.iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+
+        let page = log.get_transactions(6, 10);
+        assert_eq!(page.total, 7);
+        assert_eq!(page.entries.len(), 1);
+        assert!(matches!(page.entries[0].kind, TransactionKind::Burn));
+
+        assert!(log.get_transactions(7, 10).entries.is_empty());
+
+        // the self-transfer is listed once
+        let page = log.get_account_transactions(&alice, 0, 10);
+        assert_eq!(page.total, 6);
+        let ids: Vec<u64> = page.entries.iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4, 5]);
+
+        let page = log.get_account_transactions(&bob, 1, 2);
+        assert_eq!(page.total, 5);
+        let ids: Vec<u64> = page.entries.iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        let page = log.get_account_transactions(&bob, 5, 2);
+        assert_eq!(page.total, 5);
+        assert!(page.entries.is_empty());
+
+        let page = log.get_account_transactions(&stranger, 0, 10);
+        assert_eq!(page.total, 0);
+        assert!(page.entries.is_empty());
+    }
+
+    #[test]
+    fn transfers_with_created_at_are_deduplicated() {
+        let alice = principal(1);
+        let bob = principal(2);
+
+        let now = 2 * TRANSACTION_WINDOW;
+        let entry = |qty: Balance, created_at: u64| FungibleTokenTransferEntry {
+            to: bob,
+            qty,
+            memo: Some(vec![1]),
+            created_at: Some(created_at),
+        };
+
+        let mut token = test_token(alice);
+        token.mint(transfer(alice, 100), alice, now).unwrap();
+
+        let event = token.send(alice, entry(10, now), now).unwrap();
+        assert!(matches!(
+            token.send(alice, entry(10, now), now + 1),
+            Err(Error::Duplicate { tx_id }) if tx_id == event.seq
+        ));
+
+        // any other field makes it a different transfer
+        token.send(alice, entry(11, now), now + 1).unwrap();
+        let without_memo = FungibleTokenTransferEntry {
+            memo: None,
+            ..entry(10, now)
+        };
+        token.send(alice, without_memo, now + 1).unwrap();
+        assert_eq!(token.balance_of(&alice), 69);
+
+        assert!(matches!(
+            token.send(alice, entry(10, now - TRANSACTION_WINDOW - 1), now),
+            Err(Error::TooOld)
+        ));
+        token
+            .send(alice, entry(10, now - TRANSACTION_WINDOW), now)
+            .unwrap();
+
+        assert!(matches!(
+            token.send(alice, entry(10, now + PERMITTED_DRIFT + 1), now),
+            Err(Error::CreatedInFuture)
+        ));
+        token
+            .send(alice, entry(10, now + PERMITTED_DRIFT), now)
+            .unwrap();
+        assert_eq!(token.balance_of(&alice), 49);
+
+        // once the window is over, the transfer is rejected as too old and its key is pruned
+        let later = now + PERMITTED_DRIFT + TRANSACTION_WINDOW + 1;
+        assert!(matches!(
+            token.send(alice, entry(10, now), later),
+            Err(Error::TooOld)
+        ));
+        assert!(!token.deduplicator.index.contains_key(&TransferKey {
+            caller: alice,
+            from: Some(alice),
+            to: Some(bob),
+            qty: 10,
+            memo: Some(vec![1]),
+            created_at: now,
+        }));
+
+        // transfers without created_at are never deduplicated
+        token.send(alice, transfer(bob, 1), later).unwrap();
+        token.send(alice, transfer(bob, 1), later).unwrap();
+        assert_eq!(token.balance_of(&alice), 47);
+    }
+
+    #[test]
+    fn supply_is_capped() {
+        let alice = principal(1);
+        let bob = principal(2);
+
+        let mut token = test_token(alice);
+        token.mint(transfer(alice, Balance::MAX), alice, 0).unwrap();
+        assert!(matches!(
+            token.mint(transfer(bob, 1), alice, 0),
+            Err(Error::SupplyOverflow)
+        ));
+        assert_eq!(token.total_supply, Balance::MAX);
+        assert_eq!(token.balance_of(&bob), 0);
+
+        let mut token = test_token(alice);
+        token.info.max_supply = Some(100);
+        token.mint(transfer(alice, 60), alice, 0).unwrap();
+        assert!(matches!(
+            token.mint(transfer(bob, 41), alice, 0),
+            Err(Error::MaxSupplyExceeded)
+        ));
+        token.mint(transfer(bob, 40), alice, 0).unwrap();
+        assert_eq!(token.total_supply, 100);
+
+        // burnt tokens can be minted again
+        token.burn(bob, 10, 0).unwrap();
+        token.mint(transfer(bob, 10), alice, 0).unwrap();
+        assert_eq!(token.total_supply, 100);
+    }
+
+    #[test]
+    fn max_supply_cannot_be_set_below_the_total_supply() {
+        let alice = principal(1);
+        let bob = principal(2);
+
+        let mut token = test_token(alice);
+        token.mint(transfer(alice, 50), alice, 0).unwrap();
+
+        let capped = |max_supply| FungibleTokenInfo {
+            max_supply,
+            ..token.info.clone()
+        };
+        let below = capped(Some(49));
+        let exact = capped(Some(50));
+
+        assert!(matches!(
+            token.update_info(exact.clone(), bob),
+            Err(Error::AccessDenied)
+        ));
+        assert!(matches!(
+            token.update_info(below, alice),
+            Err(Error::MaxSupplyExceeded)
+        ));
+        assert_eq!(token.info.max_supply, None);
+
+        let old_info = token.update_info(exact, alice).unwrap();
+        assert_eq!(old_info.max_supply, None);
+        assert_eq!(token.info.max_supply, Some(50));
+        assert!(matches!(
+            token.mint(transfer(alice, 1), alice, 0),
+            Err(Error::MaxSupplyExceeded)
+        ));
+    }
+
+    #[test]
+    fn failed_sends_leave_balances_untouched() {
+        let alice = principal(1);
+        let bob = principal(2);
+
+        let mut token = test_token(alice);
+        token.mint(transfer(alice, 50), alice, 0).unwrap();
+
+        assert!(matches!(
+            token.send(alice, transfer(bob, 51), 0),
+            Err(Error::InsufficientBalance)
+        ));
+        assert!(matches!(
+            token.send(alice, transfer(alice, 51), 0),
+            Err(Error::InsufficientBalance)
+        ));
+        assert_eq!(token.balance_of(&alice), 50);
+        assert_eq!(token.balance_of(&bob), 0);
+        assert_eq!(token.transaction_log.next_id(), 1);
+
+        token.send(alice, transfer(alice, 50), 0).unwrap();
+        assert_eq!(token.balance_of(&alice), 50);
+        assert_eq!(token.transaction_log.next_id(), 2);
+    }
+
+    #[test]
+    fn batches_are_applied_all_or_nothing() {
+        let alice = principal(1);
+        let bob = principal(2);
+        let carol = principal(3);
+
+        let entry =
+            |to: Principal, qty: Balance, created_at: Option<u64>| FungibleTokenTransferEntry {
+                to,
+                qty,
+                memo: None,
+                created_at,
+            };
+
+        let mut token = test_token(alice);
+        token.mint(transfer(alice, 100), alice, 0).unwrap();
+        token.send(alice, entry(carol, 5, Some(0)), 0).unwrap();
+        token
+            .subscribe_on_move(listener(any_move(), alice, "on_move"), alice)
+            .unwrap();
+
+        let rejected = token
+            .send_batch(alice, vec![entry(bob, 60, None), entry(carol, 36, None)], 0)
+            .unwrap_err();
+        assert!(rejected.index.is_none());
+        assert!(matches!(rejected.error, Error::InsufficientBalance));
+
+        // the last entry repeats an already committed transfer
+        let rejected = token
+            .send_batch(
+                alice,
+                vec![
+                    entry(bob, 10, None),
+                    entry(carol, 10, Some(0)),
+                    entry(carol, 5, Some(0)),
+                ],
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(rejected.index, Some(2));
+        assert!(matches!(rejected.error, Error::Duplicate { tx_id: 1 }));
+
+        let rejected = token
+            .send_batch(
+                alice,
+                vec![
+                    entry(carol, 10, Some(1)),
+                    entry(bob, 10, None),
+                    entry(carol, 10, Some(1)),
+                ],
+                0,
+            )
+            .unwrap_err();
+        assert_eq!(rejected.index, Some(2));
+        assert!(matches!(
+            rejected.error,
+            Error::DuplicateInBatch { index: 0 }
+        ));
+
+        // nothing of the rejected batches is applied, logged or sent to the listeners
+        assert_eq!(token.balance_of(&alice), 95);
+        assert_eq!(token.balance_of(&bob), 0);
+        assert_eq!(token.balance_of(&carol), 5);
+        assert_eq!(token.transaction_log.next_id(), 2);
+        assert!(token
+            .on_move_listeners
+            .take_pending_deliveries(0)
+            .is_empty());
+
+        token
+            .send_batch(
+                alice,
+                vec![entry(bob, 60, None), entry(carol, 35, Some(1))],
+                0,
+            )
+            .unwrap();
+        assert_eq!(token.balance_of(&alice), 0);
+        assert_eq!(token.balance_of(&bob), 60);
+        assert_eq!(token.balance_of(&carol), 40);
+
+        let deliveries = token.on_move_listeners.take_pending_deliveries(0);
+        assert_eq!(deliveries.len(), 1);
+        let seqs: Vec<u64> = deliveries[0].events.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+    }
+}
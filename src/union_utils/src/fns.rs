@@ -3,9 +3,10 @@ use std::time::{Duration, UNIX_EPOCH};
 use chrono::prelude::DateTime;
 use chrono::Utc;
 use futures::future::join_all;
-use ic_cdk::api::call::{call_raw, CallResult};
+use ic_cdk::api::call::call_raw;
 use ic_cdk::api::time;
-use ic_cdk::export::candid::{IDLArgs, Principal};
+use ic_cdk::export::candid::parser::value::IDLValue;
+use ic_cdk::export::candid::{check_prog, idl_hash, IDLArgs, IDLProg, Principal, TypeEnv};
 use ic_cdk::{call, caller, print, trap};
 
 use crate::types::*;
@@ -26,21 +27,245 @@ pub fn log(msg: &str) {
     ))
 }
 
-pub async fn remote_call(entry: RemoteCallPayload) -> Result<Vec<u8>, RemoteCallError> {
-    let idl_args = entry
-        .idl_str_args
+pub fn parse_idl_args(args: &RemoteCallArgs) -> Result<IDLArgs, RemoteCallError> {
+    match args {
+        RemoteCallArgs::CandidString(idl_str_args) => idl_str_args
+            .parse::<IDLArgs>()
+            .map_err(|_| RemoteCallError::UnableToParseArgs),
+        RemoteCallArgs::Encoded(encoded) => {
+            let idl_args = IDLArgs::from_bytes(encoded.raw.as_slice())
+                .map_err(|_| RemoteCallError::UnableToDecodeArgs)?;
+
+            // voters read the rendering, so it should describe exactly the bytes that will be sent
+            if idl_args.to_string() != encoded.rendered {
+                return Err(RemoteCallError::ArgsRenderingMismatch);
+            }
+
+            Ok(idl_args)
+        }
+        RemoteCallArgs::Template(_) => Err(RemoteCallError::UnableToResolveTemplate(String::from(
+            "Template args should be resolved before the call",
+        ))),
+    }
+}
+
+pub fn serialize_args(args: &RemoteCallArgs) -> Result<(IDLArgs, Vec<u8>), RemoteCallError> {
+    let idl_args = parse_idl_args(args)?;
+
+    let raw_args = match args {
+        RemoteCallArgs::Encoded(encoded) => encoded.raw.clone(),
+        _ => idl_args
+            .to_bytes()
+            .map_err(|_| RemoteCallError::UnableToSerializeArgs)?,
+    };
+
+    Ok((idl_args, raw_args))
+}
+
+pub fn validate_args(args: &RemoteCallArgs, step: usize) -> Result<(), RemoteCallError> {
+    match args {
+        RemoteCallArgs::Template(template) => check_template(template.as_str(), step),
+        _ => serialize_args(args).map(|_| ()),
+    }
+}
+
+pub fn resolve_args(
+    args: RemoteCallArgs,
+    results: &[Option<&RemoteCallResult>],
+) -> Result<RemoteCallArgs, RemoteCallError> {
+    match args {
+        RemoteCallArgs::Template(template) => Ok(RemoteCallArgs::CandidString(render_template(
+            template.as_str(),
+            results,
+        )?)),
+        _ => Ok(args),
+    }
+}
+
+enum TemplateChunk<'a> {
+    Text(&'a str),
+    Ref { step: usize, path: Vec<&'a str> },
+}
+
+fn template_error(msg: String) -> RemoteCallError {
+    RemoteCallError::UnableToResolveTemplate(msg)
+}
+
+// references look like ${<step>.<arg>.<field>...}, e.g. ${0.0.canister_id}
+fn parse_template(template: &str) -> Result<Vec<TemplateChunk<'_>>, RemoteCallError> {
+    let mut chunks = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        chunks.push(TemplateChunk::Text(&rest[..start]));
+
+        let reference = &rest[start + 2..];
+        let end = reference
+            .find('}')
+            .ok_or_else(|| template_error(format!("Unclosed reference in: {}", template)))?;
+
+        let mut segments = reference[..end].split('.').map(str::trim);
+        let step = segments
+            .next()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or_else(|| template_error(format!("Invalid reference: {}", &reference[..end])))?;
+        let path: Vec<_> = segments.collect();
+
+        if path.is_empty() {
+            return Err(template_error(format!(
+                "Reference should point to an argument: {}",
+                &reference[..end]
+            )));
+        }
+
+        chunks.push(TemplateChunk::Ref { step, path });
+        rest = &reference[end + 1..];
+    }
+
+    chunks.push(TemplateChunk::Text(rest));
+
+    Ok(chunks)
+}
+
+fn check_template(template: &str, step: usize) -> Result<(), RemoteCallError> {
+    let mut substituted = String::new();
+
+    for chunk in parse_template(template)? {
+        match chunk {
+            TemplateChunk::Text(text) => substituted.push_str(text),
+            TemplateChunk::Ref { step: ref_step, .. } => {
+                if ref_step >= step {
+                    return Err(template_error(format!(
+                        "Step {} can only reference previous steps, but references step {}",
+                        step, ref_step
+                    )));
+                }
+
+                // real values are only known at execution time - any value keeps the syntax valid
+                substituted.push_str("null");
+            }
+        }
+    }
+
+    substituted
         .parse::<IDLArgs>()
-        .map_err(|_| RemoteCallError::UnableToParseArgs)?;
+        .map(|_| ())
+        .map_err(|_| RemoteCallError::UnableToParseArgs)
+}
+
+fn render_template(
+    template: &str,
+    results: &[Option<&RemoteCallResult>],
+) -> Result<String, RemoteCallError> {
+    let mut rendered = String::new();
 
-    let raw_args = idl_args
-        .to_bytes()
-        .map_err(|_| RemoteCallError::UnableToSerializeArgs)?;
+    for chunk in parse_template(template)? {
+        match chunk {
+            TemplateChunk::Text(text) => rendered.push_str(text),
+            TemplateChunk::Ref { step, path } => {
+                let raw = match results.get(step) {
+                    Some(Some(Ok(raw))) => raw,
+                    _ => {
+                        return Err(template_error(format!(
+                            "Step {} has no successful result",
+                            step
+                        )))
+                    }
+                };
+
+                let idl_args = IDLArgs::from_bytes(raw.as_slice()).map_err(|_| {
+                    template_error(format!("Unable to decode the result of step {}", step))
+                })?;
+
+                let value = lookup_value(&idl_args, &path).ok_or_else(|| {
+                    template_error(format!(
+                        "Unable to find {} in the result of step {}",
+                        path.join("."),
+                        step
+                    ))
+                })?;
+
+                rendered.push_str(render_value(value).as_str());
+            }
+        }
+    }
+
+    Ok(rendered)
+}
+
+// the default rendering drops the types of numbers, so the rendered args would be encoded
+// with other types than the result was decoded with
+fn render_value(value: &IDLValue) -> String {
+    match value {
+        IDLValue::Int(n) => format!("({} : int)", n),
+        IDLValue::Nat(n) => format!("({} : nat)", n),
+        IDLValue::Nat8(n) => format!("({} : nat8)", n),
+        IDLValue::Nat16(n) => format!("({} : nat16)", n),
+        IDLValue::Nat32(n) => format!("({} : nat32)", n),
+        IDLValue::Nat64(n) => format!("({} : nat64)", n),
+        IDLValue::Int8(n) => format!("({} : int8)", n),
+        IDLValue::Int16(n) => format!("({} : int16)", n),
+        IDLValue::Int32(n) => format!("({} : int32)", n),
+        IDLValue::Int64(n) => format!("({} : int64)", n),
+        IDLValue::Float32(n) => format!("({} : float32)", n),
+        IDLValue::Float64(n) => format!("({} : float64)", n),
+        IDLValue::Opt(inner) => format!("opt {}", render_value(inner)),
+        IDLValue::Vec(values) => {
+            let values: Vec<_> = values.iter().map(render_value).collect();
+
+            format!("vec {{ {} }}", values.join("; "))
+        }
+        IDLValue::Record(fields) => {
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|f| format!("{} = {}", f.id, render_value(&f.val)))
+                .collect();
+
+            format!("record {{ {} }}", fields.join("; "))
+        }
+        IDLValue::Variant(field, _) => {
+            format!("variant {{ {} = {} }}", field.id, render_value(&field.val))
+        }
+        _ => value.to_string(),
+    }
+}
+
+fn lookup_value<'a>(idl_args: &'a IDLArgs, path: &[&str]) -> Option<&'a IDLValue> {
+    let (arg, fields) = path.split_first()?;
+    let mut value = idl_args.args.get(arg.parse::<usize>().ok()?)?;
+
+    for field in fields {
+        // options are transparent for the lookup
+        while let IDLValue::Opt(inner) = value {
+            value = inner;
+        }
+
+        // decoded values only carry label hashes, tuple fields are labeled with their position
+        let id = field.parse::<u32>().unwrap_or_else(|_| idl_hash(field));
+
+        value = match value {
+            IDLValue::Vec(values) => values.get(id as usize)?,
+            IDLValue::Record(record_fields) => {
+                &record_fields.iter().find(|f| f.id.get_id() == id)?.val
+            }
+            IDLValue::Variant(variant_field, _) if variant_field.id.get_id() == id => {
+                &variant_field.val
+            }
+            _ => return None,
+        };
+    }
+
+    Some(value)
+}
+
+pub async fn remote_call(entry: RemoteCallPayload) -> Result<Vec<u8>, RemoteCallError> {
+    let (idl_args, raw_args) = serialize_args(&entry.call_args()?)?;
 
     log(format!(
         "Calling remote canister: {}.{}{}",
         entry.endpoint.canister_id.to_text(),
         entry.endpoint.method_name,
-        idl_args.to_string()
+        idl_args
     )
     .as_str());
 
@@ -58,6 +283,49 @@ pub async fn remote_call(entry: RemoteCallPayload) -> Result<Vec<u8>, RemoteCall
     Ok(result)
 }
 
+pub fn decode_result(result: RemoteCallResult) -> DecodedRemoteCallResult {
+    result.map(|raw| {
+        // results of methods with unknown signatures can't always be decoded - keep them raw then
+        let decoded = IDLArgs::from_bytes(raw.as_slice())
+            .ok()
+            .map(|idl_args| idl_args.to_string());
+
+        DecodedRemoteCallOutput { raw, decoded }
+    })
+}
+
+pub async fn get_candid_interface(canister_id: Principal) -> Result<String, CandidInterfaceError> {
+    call::<_, (String,)>(canister_id, "__get_candid_interface_tmp_hack", ())
+        .await
+        .map(|(interface,)| interface)
+        .map_err(|(_, err)| CandidInterfaceError::InterfaceIsUnavailable(err))
+}
+
+pub fn check_args_against_interface(
+    idl_args: IDLArgs,
+    candid_interface: &str,
+    method_name: &str,
+) -> Result<(), CandidInterfaceError> {
+    let prog = candid_interface
+        .parse::<IDLProg>()
+        .map_err(|e| CandidInterfaceError::UnableToParseInterface(e.to_string()))?;
+
+    let mut env = TypeEnv::new();
+    let actor = check_prog(&mut env, &prog)
+        .map_err(|e| CandidInterfaceError::UnableToParseInterface(e.to_string()))?
+        .ok_or(CandidInterfaceError::ServiceIsNotDefined)?;
+
+    let method = env
+        .get_method(&actor, method_name)
+        .map_err(|_| CandidInterfaceError::MethodDoesNotExist)?;
+
+    idl_args
+        .annotate_types(true, &env, &method.args)
+        .map_err(|e| CandidInterfaceError::ArgsTypeMismatch(e.to_string()))?;
+
+    Ok(())
+}
+
 pub fn only_by(controller_opt: Option<Principal>) {
     if let Some(controller) = controller_opt {
         if controller != caller() {
@@ -70,24 +338,149 @@ pub fn is_passing_threshold(small: u64, big: u64, threshold: f64) -> bool {
     small as f64 / big as f64 >= threshold
 }
 
-pub async fn send_events(
-    ev_and_listeners: TokenMoveEventAndListeners,
-) -> Option<Vec<CallResult<()>>> {
-    if ev_and_listeners.listeners.is_empty() {
-        return None;
+// requests the events a listener has missed from the emitter's log, see EventSequenceTracker
+pub async fn fetch_missing_events(
+    emitter: Principal,
+    from_seq: u64,
+    limit: u64,
+) -> Result<Vec<TokenMoveEvent>, String> {
+    call::<_, (Vec<TokenMoveEvent>,)>(emitter, "get_events", (from_seq, limit))
+        .await
+        .map(|(events,)| events)
+        .map_err(|(_, e)| e)
+}
+
+// sends the new events along with the ones that failed to be delivered before;
+// the listeners are passed as a getter, since the state may change while the calls are awaited
+pub async fn deliver_pending_events(listeners: fn() -> &'static mut OnMoveListenersInfo) {
+    let deliveries = listeners().take_pending_deliveries(time());
+
+    if deliveries.is_empty() {
+        return;
+    }
+
+    for report in deliver_events(deliveries).await {
+        listeners().complete_delivery(report, time());
     }
+}
+
+// events of different listeners are sent concurrently, the ones of the same listener - sequentially,
+// stopping at the first failure, so they always arrive in order;
+// listeners reply with variant { Ok; Err : ... } and an event they reject is sent again later
+pub async fn deliver_events(deliveries: Vec<PendingDelivery>) -> Vec<DeliveryReport> {
+    let fs: Vec<_> = deliveries
+        .into_iter()
+        .map(|delivery| async move {
+            let mut report = DeliveryReport {
+                listener_id: delivery.listener_id,
+                delivered: 0,
+                error: None,
+            };
+
+            for event in delivery.events {
+                let result = call::<_, (Result<(), IDLValue>,)>(
+                    delivery.endpoint.canister_id,
+                    delivery.endpoint.method_name.as_str(),
+                    (event,),
+                )
+                .await;
 
-    let fs: Vec<_> = ev_and_listeners
-        .listeners
-        .iter()
-        .map(|listener| {
-            call::<_, ()>(
-                listener.endpoint.canister_id,
-                listener.endpoint.method_name.as_str(),
-                (ev_and_listeners.event.clone(),),
-            )
+                match result {
+                    Ok((Ok(()),)) => report.delivered += 1,
+                    Ok((Err(e),)) => {
+                        report.error = Some(format!("Rejected by the listener: {}", e));
+                        break;
+                    }
+                    Err((_, e)) => {
+                        report.error = Some(e);
+                        break;
+                    }
+                }
+            }
+
+            report
         })
         .collect();
 
-    return Some(join_all(fs).await);
+    join_all(fs).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result_of(idl_str_args: &str) -> RemoteCallResult {
+        Ok(idl_str_args.parse::<IDLArgs>().unwrap().to_bytes().unwrap())
+    }
+
+    fn unable_to_resolve(result: Result<String, RemoteCallError>) -> bool {
+        matches!(result, Err(RemoteCallError::UnableToResolveTemplate(_)))
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        assert!(parse_template("(${0.0)").is_err());
+        assert!(parse_template("(${a.0})").is_err());
+        assert!(parse_template("(${0})").is_err());
+        assert_eq!(parse_template("(1, ${0.0.id}, ${1.2})").unwrap().len(), 5);
+
+        assert!(check_template("(${0.0}, \"a\")", 1).is_ok());
+        assert!(matches!(
+            check_template("(${1.0})", 1),
+            Err(RemoteCallError::UnableToResolveTemplate(_))
+        ));
+        assert!(matches!(
+            check_template("(${0.0}", 1),
+            Err(RemoteCallError::UnableToParseArgs)
+        ));
+    }
+
+    #[test]
+    fn templates_are_rendered_from_previous_results() {
+        let first = result_of(
+            "(record { canister_id = principal \"aaaaa-aa\"; amount = opt (5 : nat64) }, vec { 7 : nat64; 8 : nat64 })",
+        );
+        let second = result_of("(variant { Ok = 3 : nat64 })");
+        let results = vec![Some(&first), Some(&second)];
+
+        let rendered = render_template(
+            "(${0.0.canister_id}, ${0.0.amount}, ${0.1.1}, ${1.0.Ok})",
+            &results,
+        )
+        .unwrap();
+        let rendered = rendered.parse::<IDLArgs>().unwrap();
+        let expected = "(principal \"aaaaa-aa\", opt (5 : nat64), 8 : nat64, 3 : nat64)"
+            .parse::<IDLArgs>()
+            .unwrap();
+        assert_eq!(rendered.args, expected.args);
+
+        // whole records keep the types of their fields
+        let rendered = render_template("(${0.0})", &results).unwrap();
+        let rendered = rendered.parse::<IDLArgs>().unwrap().to_bytes().unwrap();
+        let decoded = IDLArgs::from_bytes(&rendered).unwrap();
+        let original = IDLArgs::from_bytes(first.as_ref().unwrap()).unwrap();
+        assert_eq!(decoded.args[0], original.args[0]);
+    }
+
+    #[test]
+    fn unresolvable_references_are_rejected() {
+        let ok = result_of("(record { amount = 5 : nat64 }, variant { Ok = 3 : nat64 })");
+        let failed: RemoteCallResult = Err(RemoteCallError::RemoteCallReject(String::from("")));
+        let results = vec![Some(&ok), Some(&failed), None];
+
+        // a failed, a skipped and a missing step
+        assert!(unable_to_resolve(render_template("(${1.0})", &results)));
+        assert!(unable_to_resolve(render_template("(${2.0})", &results)));
+        assert!(unable_to_resolve(render_template("(${3.0})", &results)));
+
+        // bad paths: no such arg, non-numeric arg, no such field, another variant, field of a number
+        assert!(unable_to_resolve(render_template("(${0.2})", &results)));
+        assert!(unable_to_resolve(render_template("(${0.x})", &results)));
+        assert!(unable_to_resolve(render_template("(${0.0.qty})", &results)));
+        assert!(unable_to_resolve(render_template("(${0.1.Err})", &results)));
+        assert!(unable_to_resolve(render_template(
+            "(${0.0.amount.x})",
+            &results
+        )));
+    }
 }
@@ -1,3 +1,5 @@
 pub mod fns;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod traits;
 pub mod types;
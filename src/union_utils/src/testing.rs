@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use ic_cdk::export::candid::{decode_one, encode_one, CandidType, Deserialize};
+use ic_cdk::export::Principal;
+
+use crate::types::{
+    Account, AccountFilterV1, Filter, FilterV1, OnMoveListener, OnMoveListenerV1,
+    OnMoveListenersInfoV1, RemoteCallEndpoint, TokenMoveEvent,
+};
+
+pub fn principal(id: u8) -> Principal {
+    Principal::from_slice(&[id])
+}
+
+// an event as the emitter logs it - the one before it is its prev_seq
+pub fn move_event(seq: u64, from: Account, to: Account, qty: u128) -> TokenMoveEvent {
+    TokenMoveEvent {
+        seq,
+        timestamp: 0,
+        from,
+        to,
+        qty,
+        memo: None,
+        prev_seq: seq.checked_sub(1),
+    }
+}
+
+pub fn any_move() -> Filter {
+    Filter {
+        from: None,
+        to: None,
+        kinds: None,
+        min_qty: None,
+        max_qty: None,
+    }
+}
+
+pub fn listener(filter: Filter, canister_id: Principal, method_name: &str) -> OnMoveListener {
+    OnMoveListener {
+        filter,
+        endpoint: RemoteCallEndpoint {
+            canister_id,
+            method_name: String::from(method_name),
+        },
+    }
+}
+
+// the listeners of a token saved before the V2 filters, with a single one subscribed under the id
+pub fn v1_listeners(
+    id: u64,
+    from: AccountFilterV1,
+    to: AccountFilterV1,
+    canister_id: Principal,
+) -> OnMoveListenersInfoV1 {
+    let mut enumeration = HashMap::new();
+    enumeration.insert(
+        id,
+        OnMoveListenerV1 {
+            filter: FilterV1 { from, to },
+            endpoint: RemoteCallEndpoint {
+                canister_id,
+                method_name: String::from("on_move"),
+            },
+        },
+    );
+
+    OnMoveListenersInfoV1 {
+        id_counter: id + 1,
+        enumeration,
+        index: HashMap::new(),
+    }
+}
+
+// what post_upgrade gets back from the state pre_upgrade saved
+pub fn stable_round_trip<T>(saved: T) -> T
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    let bytes = encode_one(saved).unwrap();
+
+    decode_one(&bytes).unwrap()
+}
@@ -1,36 +1,19 @@
 use ic_cdk::export::candid::Principal;
 
-use crate::types::{RemoteCallPayload, RemoteCallResult, VotingId};
+use crate::types::VotingId;
 
 /*
-type RemoteCallEndpoint = record {
-    canister_id: principal;
-    method_name: text;
-};
-
-type RemoteCallPayload = record {
-    endpoint: RemoteCallEndpoint;
-    idl_str_args: text;
-    payment: int64;
-};
-
-type RemoteCallError = variant {
-    UnableToParseArgs;
-    UnableToSerializeArgs;
-    RemoteCallReject : text;
-};
-
-type RemoteCallResult = variant {
-    Ok : blob;
-    Err : RemoteCallError;
-};
-
 service : {
-    "_union_call" : (vec RemoteCallPayload, nat32) -> (vec RemoteCallResult);
+    "_union_call" : (UnionCallPayload) -> (UnionCallResult);
 }
  */
+// UnionCallPayload and UnionCallResult are defined in union_wallet.did - the program's
+// execution details (stages, policies, assertions) belong to the wallet
 pub trait IUnionWallet {
-    fn _union_call(p: Vec<RemoteCallPayload>, voting_id: VotingId) -> Vec<RemoteCallResult>;
+    type UnionCallPayload;
+    type UnionCallResult;
+
+    fn _union_call(payload: Self::UnionCallPayload) -> Self::UnionCallResult;
 }
 
 /*
@@ -63,4 +46,4 @@ pub trait IEventListener {
     fn _union_on_voting_updated(id: VotingId);
     fn _union_on_voting_state_changed(id: VotingId);
     fn _union_on_vote_placed(id: VotingId);
-}
\ No newline at end of file
+}
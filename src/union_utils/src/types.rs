@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 
 use ic_cdk::export::candid::{CandidType, Deserialize};
 use ic_cdk::export::Principal;
@@ -15,32 +15,101 @@ pub struct RemoteCallEndpoint {
     pub method_name: String,
 }
 
+/*
+type EncodedArgs = record {
+     raw: blob;
+     rendered: text;
+};
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EncodedArgs {
+    pub raw: Vec<u8>,
+    pub rendered: String,
+}
+
+/*
+type RemoteCallArgs = variant {
+     CandidString : text;
+     Encoded : EncodedArgs;
+     Template : text;
+};
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum RemoteCallArgs {
+    CandidString(String),
+    Encoded(EncodedArgs),
+    Template(String),
+}
+
 /*
 type RemoteCallPayload = record {
      endpoint: RemoteCallEndpoint;
-     idl_str_args: text;
+     idl_str_args: opt text;
+     args: opt RemoteCallArgs;
      payment: nat64;
 };
 */
+// exactly one of the args forms should be set - idl_str_args is the original textual form,
+// kept so payloads built before RemoteCallArgs existed are still accepted as they are
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct RemoteCallPayload {
     pub endpoint: RemoteCallEndpoint,
-    pub idl_str_args: String,
+    pub idl_str_args: Option<String>,
+    pub args: Option<RemoteCallArgs>,
     pub payment: u64,
 }
 
+impl RemoteCallPayload {
+    pub fn call_args(&self) -> Result<RemoteCallArgs, RemoteCallError> {
+        match (&self.idl_str_args, &self.args) {
+            (Some(idl_str_args), None) => Ok(RemoteCallArgs::CandidString(idl_str_args.clone())),
+            (None, Some(args)) => Ok(args.clone()),
+            (None, None) => Err(RemoteCallError::MissingArgs),
+            (Some(_), Some(_)) => Err(RemoteCallError::ConflictingArgs),
+        }
+    }
+}
+
 /*
 type RemoteCallError = variant {
      UnableToParseArgs;
      UnableToSerializeArgs;
+     UnableToDecodeArgs;
+     ArgsRenderingMismatch;
+     UnableToResolveTemplate : text;
      RemoteCallReject : text;
+     MissingArgs;
+     ConflictingArgs;
 };
 */
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum RemoteCallError {
     UnableToParseArgs,
     UnableToSerializeArgs,
+    UnableToDecodeArgs,
+    ArgsRenderingMismatch,
+    UnableToResolveTemplate(String),
     RemoteCallReject(String),
+    MissingArgs,
+    ConflictingArgs,
+}
+
+/*
+type CandidInterfaceError = variant {
+     InterfaceIsUnavailable : text;
+     UnableToParseInterface : text;
+     ServiceIsNotDefined;
+     MethodDoesNotExist;
+     ArgsTypeMismatch : text;
+};
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum CandidInterfaceError {
+    InterfaceIsUnavailable(String),
+    UnableToParseInterface(String),
+    ServiceIsNotDefined,
+    MethodDoesNotExist,
+    ArgsTypeMismatch(String),
 }
 
 /*
@@ -51,6 +120,26 @@ type RemoteCallResult = variant {
 */
 pub type RemoteCallResult = Result<Vec<u8>, RemoteCallError>;
 
+/*
+type DecodedRemoteCallOutput = record {
+     raw : blob;
+     decoded : opt text;
+};
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DecodedRemoteCallOutput {
+    pub raw: Vec<u8>,
+    pub decoded: Option<String>,
+}
+
+/*
+type DecodedRemoteCallResult = variant {
+     Ok : DecodedRemoteCallOutput;
+     Err : RemoteCallError;
+};
+*/
+pub type DecodedRemoteCallResult = Result<DecodedRemoteCallOutput, RemoteCallError>;
+
 /*
 type Controlled_* = record {
      data : *;
@@ -79,7 +168,7 @@ impl<T> Controlled<T> {
     }
 
     pub fn is_controller(&self, principal: Principal) -> bool {
-        if let Some(controller) = self.controller.clone() {
+        if let Some(controller) = self.controller {
             return controller == principal;
         }
 
@@ -105,7 +194,7 @@ type VotingId = record {
      idx : nat64;
 };
 */
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Hash, Eq, PartialEq, Debug, CandidType, Deserialize)]
 pub struct VotingId {
     pub union_wallet: Principal,
     pub idx: usize,
@@ -121,42 +210,218 @@ pub type Account = Option<Principal>;
 
 /*
 type TokenMoveEvent = record {
+     seq : nat64;
+     timestamp : nat64;
      from : Account;
      to : Account;
-     qty : nat64;
+     qty : nat;
+     memo : opt blob;
+     prev_seq : opt nat64;
 };
 */
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct TokenMoveEvent {
+    // position of the event among all the events of the emitter, starting from 0
+    pub seq: u64,
+    pub timestamp: u64,
     pub from: Account,
     pub to: Account,
-    pub qty: u64,
+    // candid nat, wide enough for the amounts of tokens with 18 decimals
+    pub qty: u128,
+    pub memo: Option<Vec<u8>>,
+    // seq of the previous event sent to the same listener - the events in between didn't match
+    // its filter; in the emitter's log it's just the previous seq
+    pub prev_seq: Option<u64>,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct TokenMoveEventAndListeners {
-    pub event: TokenMoveEvent,
-    pub listeners: Vec<OnMoveListener>,
+impl TokenMoveEvent {
+    pub fn kind(&self) -> TokenMoveKind {
+        match (&self.from, &self.to) {
+            (None, _) => TokenMoveKind::Mint,
+            (_, None) => TokenMoveKind::Burn,
+            _ => TokenMoveKind::Transfer,
+        }
+    }
+}
+
+/*
+ type TokenMoveKind = variant {
+   Mint;
+   Burn;
+   Transfer;
+ }
+*/
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum TokenMoveKind {
+    Mint,
+    Burn,
+    Transfer,
+}
+
+// only the latest events are kept, so the log and the state saved on upgrade stay bounded;
+// a listener which falls behind further than that can't catch up from the log anymore
+pub const MAX_EVENT_LOG_LENGTH: usize = 500_000;
+
+// the latest events emitted by a token, the seq of an event is its position among all of them
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct TokenMoveEventLog {
+    // seq of the oldest event which is still kept
+    pub first_seq: u64,
+    pub events: VecDeque<TokenMoveEvent>,
+}
+
+impl TokenMoveEventLog {
+    pub fn emit(
+        &mut self,
+        from: Account,
+        to: Account,
+        qty: u128,
+        memo: Option<Vec<u8>>,
+        timestamp: u64,
+    ) -> TokenMoveEvent {
+        let seq = self.next_seq();
+        let event = TokenMoveEvent {
+            seq,
+            timestamp,
+            from,
+            to,
+            qty,
+            memo,
+            prev_seq: seq.checked_sub(1),
+        };
+
+        self.events.push_back(event.clone());
+
+        if self.events.len() > MAX_EVENT_LOG_LENGTH {
+            self.events.pop_front();
+            self.first_seq += 1;
+        }
+
+        event
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.first_seq + self.events.len() as u64
+    }
+
+    // events starting from from_seq, at most MAX_EVENTS_PAGE of them; if from_seq is already pruned,
+    // the page starts from the oldest kept event and its prev_seq reveals the gap
+    pub fn get_events(&self, from_seq: u64, limit: u64) -> Vec<TokenMoveEvent> {
+        self.events
+            .iter()
+            .skip(from_seq.saturating_sub(self.first_seq) as usize)
+            .take(limit.min(MAX_EVENTS_PAGE) as usize)
+            .cloned()
+            .collect()
+    }
+}
+
+/*
+ type SequenceCheck = variant {
+   InOrder;
+   AlreadyApplied;
+   Gap : record { from_seq : nat64; to_seq : nat64; };
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum SequenceCheck {
+    InOrder,
+    AlreadyApplied,
+    // seqs of the missing events, the end is exclusive
+    Gap { from_seq: u64, to_seq: u64 },
+}
+
+// kept by a listener to apply the events of each emitter exactly once and in order;
+// events skipped by the listener's filter are not a gap, since prev_seq points over them
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct EventSequenceTracker {
+    pub next_seqs: HashMap<Principal, u64>,
+}
+
+impl EventSequenceTracker {
+    pub fn next_seq(&self, emitter: &Principal) -> u64 {
+        self.next_seqs.get(emitter).cloned().unwrap_or(0)
+    }
+
+    pub fn is_tracked(&self, emitter: &Principal) -> bool {
+        self.next_seqs.contains_key(emitter)
+    }
+
+    pub fn check(&self, emitter: &Principal, event: &TokenMoveEvent) -> SequenceCheck {
+        let next_seq = self.next_seq(emitter);
+
+        if event.seq < next_seq {
+            SequenceCheck::AlreadyApplied
+        } else if event.prev_seq.is_none_or(|prev_seq| prev_seq < next_seq) {
+            SequenceCheck::InOrder
+        } else {
+            SequenceCheck::Gap {
+                from_seq: next_seq,
+                to_seq: event.seq,
+            }
+        }
+    }
+
+    pub fn mark_applied(&mut self, emitter: Principal, event: &TokenMoveEvent) {
+        self.next_seqs.insert(emitter, event.seq + 1);
+    }
 }
 
 /*
  type AccountFilter = variant {
    None;
-   Some : Account;
+   Some : vec Account;
  }
 */
-pub type AccountFilter = Option<Account>;
+// None matches any account, otherwise the account has to be one of the listed
+pub type AccountFilter = Option<Vec<Account>>;
 
 /*
  type Filter = record {
    from : AccountFilter;
    to : AccountFilter;
+   kinds : opt vec TokenMoveKind;
+   min_qty : opt nat;
+   max_qty : opt nat;
  }
 */
+// an event matches the filter only if it satisfies every criteria which is set
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Filter {
     pub from: AccountFilter,
     pub to: AccountFilter,
+    pub kinds: Option<Vec<TokenMoveKind>>,
+    pub min_qty: Option<u128>,
+    pub max_qty: Option<u128>,
+}
+
+impl Filter {
+    pub fn matches(&self, event: &TokenMoveEvent) -> bool {
+        account_matches(&self.from, &event.from)
+            && account_matches(&self.to, &event.to)
+            && self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.contains(&event.kind()))
+            && self.min_qty.is_none_or(|min| event.qty >= min)
+            && self.max_qty.is_none_or(|max| event.qty <= max)
+    }
+
+    // a filter which can't match anything is most probably a mistake
+    pub fn is_valid(&self) -> bool {
+        let accounts_valid = |f: &AccountFilter| f.as_ref().is_none_or(|set| !set.is_empty());
+        let kinds_valid = self.kinds.as_ref().is_none_or(|kinds| !kinds.is_empty());
+        let qty_valid = match (self.min_qty, self.max_qty) {
+            (Some(min), Some(max)) => min <= max,
+            _ => true,
+        };
+
+        accounts_valid(&self.from) && accounts_valid(&self.to) && kinds_valid && qty_valid
+    }
+}
+
+fn account_matches(filter: &AccountFilter, account: &Account) -> bool {
+    filter.as_ref().is_none_or(|set| set.contains(account))
 }
 
 /*
@@ -176,125 +441,801 @@ pub enum OnMoveListenerError {
     AccessDenied,
     ListenerDoesNotExist,
     ListenerFatalError,
+    InvalidFilter,
+}
+
+pub const MAX_DELIVERY_FAILURES: u32 = 5;
+pub const MAX_DELIVERY_BATCH: usize = 20;
+// events beyond it are dropped, the listener catches up with get_events()
+pub const MAX_OUTBOX_LENGTH: usize = 1_000;
+// a minute in nanos, multiplied by the number of consecutive failures
+pub const DELIVERY_RETRY_INTERVAL: u64 = 60_000_000_000;
+// keeps a get_events() response well below the message size limit
+pub const MAX_EVENTS_PAGE: u64 = 100;
+
+// undelivered events of a single listener, which are sent one by one in their original order
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PendingDelivery {
+    pub listener_id: u64,
+    pub endpoint: RemoteCallEndpoint,
+    pub events: Vec<TokenMoveEvent>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DeliveryReport {
+    pub listener_id: u64,
+    pub delivered: usize,
+    pub error: Option<String>,
+}
+
+/*
+ type ListenerBacklog = record {
+   listener_id : nat64;
+   listener : OnMoveListener;
+   pending_events : nat64;
+   consecutive_failures : nat32;
+   last_error : opt text;
+   disabled : bool;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ListenerBacklog {
+    pub listener_id: u64,
+    pub listener: OnMoveListener,
+    pub pending_events: usize,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub disabled: bool,
+}
+
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct DeliveryState {
+    pub outbox: VecDeque<TokenMoveEvent>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub disabled: bool,
+    // set while the events are being sent, so concurrent calls don't deliver them twice
+    pub in_flight: bool,
+    // seq of the last event meant for the listener, even if it was dropped; None until the first one
+    pub last_enqueued_seq: Option<u64>,
+    // a failed delivery isn't retried before this time
+    pub retry_at: u64,
 }
 
 #[derive(Clone, Default, Debug, CandidType, Deserialize)]
 pub struct OnMoveListenersInfo {
     pub id_counter: u64,
     pub enumeration: HashMap<u64, OnMoveListener>,
-    pub index: HashMap<AccountFilter, Vec<u64>>,
+    pub from_index: HashMap<Account, Vec<u64>>,
+    pub to_index: HashMap<Account, Vec<u64>>,
+    // listeners with no account constraints, checked for every event
+    pub unindexed: Vec<u64>,
+    pub deliveries: HashMap<u64, DeliveryState>,
 }
 
 impl OnMoveListenersInfo {
     pub fn add_listener(&mut self, listener: OnMoveListener) -> Result<u64, OnMoveListenerError> {
-        let id = self.id_counter;
-        self.enumeration.insert(id, listener.clone());
-
-        // add only one listener if there are the same filters for both 'from' and 'to'
-        if listener.filter.from == listener.filter.to {
-            let index = self
-                .index
-                .entry(listener.filter.from)
-                .or_insert_with(Vec::new);
-
-            index.push(id);
-        } else {
-            let index_from = self
-                .index
-                .entry(listener.filter.from.clone())
-                .or_insert_with(Vec::new);
-
-            index_from.push(id);
-
-            let index_to = self
-                .index
-                .entry(listener.filter.to)
-                .or_insert_with(Vec::new);
-
-            index_to.push(id);
+        if !listener.filter.is_valid() {
+            return Err(OnMoveListenerError::InvalidFilter);
         }
 
+        let id = self.id_counter;
+        self.insert_listener(id, listener);
         self.id_counter += 1;
 
         Ok(id)
     }
 
+    // ids have to be inserted in the growing order
+    fn insert_listener(&mut self, id: u64, listener: OnMoveListener) {
+        for key in IndexKey::of(&listener.filter) {
+            // ids are growing, so the index stays sorted
+            self.index_mut(key).push(id);
+        }
+
+        self.enumeration.insert(id, listener);
+        self.deliveries.insert(id, DeliveryState::default());
+    }
+
     pub fn remove_listener(&mut self, id: u64) -> Result<OnMoveListener, OnMoveListenerError> {
         let listener = self
             .enumeration
             .remove(&id)
             .ok_or(OnMoveListenerError::ListenerDoesNotExist)?;
 
-        // remove only one listener if there are the same filters for both 'from' and 'to'
-        if listener.filter.from == listener.filter.to {
-            let index = self
-                .index
-                .get_mut(&listener.filter.from)
-                .ok_or(OnMoveListenerError::ListenerFatalError)?;
-
+        for key in IndexKey::of(&listener.filter) {
+            let index = self.index_mut(key);
             let idx = index
                 .binary_search(&id)
                 .map_err(|_| OnMoveListenerError::ListenerFatalError)?;
 
             index.remove(idx);
-        } else {
-            // remove listener 'from'
-            let index_from = self
-                .index
-                .get_mut(&listener.filter.from)
-                .ok_or(OnMoveListenerError::ListenerFatalError)?;
-            let idx_from = index_from
-                .binary_search(&id)
-                .map_err(|_| OnMoveListenerError::ListenerFatalError)?;
-            index_from.remove(idx_from);
-
-            // remove listener 'to'
-            let index_to = self
-                .index
-                .get_mut(&listener.filter.to)
-                .ok_or(OnMoveListenerError::ListenerFatalError)?;
-            let idx_to = index_to
-                .binary_search(&id)
-                .map_err(|_| OnMoveListenerError::ListenerFatalError)?;
-            index_to.remove(idx_to);
         }
 
+        // accounts nobody listens to anymore don't stay in the index
+        self.from_index.retain(|_, ids| !ids.is_empty());
+        self.to_index.retain(|_, ids| !ids.is_empty());
+        self.deliveries.remove(&id);
+
         Ok(listener)
     }
 
-    pub fn get_matching_listeners(&self, event: &TokenMoveEvent) -> Vec<OnMoveListener> {
-        // collect listeners which listen for ANY sender
-        let any_ids = self
-            .index
-            .get(&AccountFilter::None)
-            .cloned()
-            .unwrap_or_else(Vec::new);
+    // disabled listeners and the ones with a full outbox miss the event, but the prev_seq of their
+    // next one still points to it; the first event of a listener keeps the emitter's prev_seq, so
+    // the listener knows whether it has missed the earlier history
+    pub fn enqueue_event(&mut self, event: TokenMoveEvent) {
+        for id in self.get_matching_listener_ids(&event) {
+            let state = self.deliveries.entry(id).or_default();
 
-        // collect listeners which listen for 'from'
-        let from_ids = self
-            .index
-            .get(&AccountFilter::Some(event.from.clone()))
-            .cloned()
-            .unwrap_or_else(Vec::new);
+            let mut listener_event = event.clone();
+            if let Some(last_enqueued_seq) = state.last_enqueued_seq {
+                listener_event.prev_seq = Some(last_enqueued_seq);
+            }
 
-        // collect listeners which listen for 'to'
-        let to_ids = self
-            .index
-            .get(&AccountFilter::Some(event.to.clone()))
-            .cloned()
-            .unwrap_or_else(Vec::new);
+            state.last_enqueued_seq = Some(event.seq);
+
+            if !state.disabled && state.outbox.len() < MAX_OUTBOX_LENGTH {
+                state.outbox.push_back(listener_event);
+            }
+        }
+    }
+
+    pub fn take_pending_deliveries(&mut self, now: u64) -> Vec<PendingDelivery> {
+        let enumeration = &self.enumeration;
+
+        self.deliveries
+            .iter_mut()
+            .filter(|(_, state)| {
+                !state.outbox.is_empty()
+                    && !state.disabled
+                    && !state.in_flight
+                    && state.retry_at <= now
+            })
+            .filter_map(|(id, state)| {
+                let listener = enumeration.get(id)?;
+                state.in_flight = true;
+
+                Some(PendingDelivery {
+                    listener_id: *id,
+                    endpoint: listener.endpoint.clone(),
+                    events: state
+                        .outbox
+                        .iter()
+                        .take(MAX_DELIVERY_BATCH)
+                        .cloned()
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn complete_delivery(&mut self, report: DeliveryReport, now: u64) {
+        // the listener could have been removed while its events were being sent
+        let state = match self.deliveries.get_mut(&report.listener_id) {
+            Some(state) => state,
+            None => return,
+        };
+
+        let delivered = report.delivered.min(state.outbox.len());
+
+        state.in_flight = false;
+        state.outbox.drain(..delivered);
+
+        match report.error {
+            Some(error) => {
+                state.consecutive_failures += 1;
+                state.last_error = Some(error);
+                state.retry_at = now + DELIVERY_RETRY_INTERVAL * state.consecutive_failures as u64;
+
+                if state.consecutive_failures >= MAX_DELIVERY_FAILURES {
+                    state.disabled = true;
+                    state.outbox.clear();
+                }
+            }
+            None => {
+                state.consecutive_failures = 0;
+            }
+        }
+    }
+
+    // deliveries interrupted by an upgrade never complete, so their events have to be sent again
+    pub fn reset_in_flight_deliveries(&mut self) {
+        for state in self.deliveries.values_mut() {
+            state.in_flight = false;
+        }
+    }
 
-        // dedup
-        let unique_ids = any_ids
+    pub fn enable_listener(&mut self, id: u64) -> Result<(), OnMoveListenerError> {
+        let state = self
+            .deliveries
+            .get_mut(&id)
+            .ok_or(OnMoveListenerError::ListenerDoesNotExist)?;
+
+        state.disabled = false;
+        state.consecutive_failures = 0;
+        state.retry_at = 0;
+
+        Ok(())
+    }
+
+    pub fn get_backlog(&self) -> Vec<ListenerBacklog> {
+        let mut backlog: Vec<_> = self
+            .deliveries
             .iter()
-            .chain(from_ids.iter())
-            .chain(to_ids.iter())
-            .collect::<HashSet<_>>();
+            .filter_map(|(id, state)| {
+                let listener = self.enumeration.get(id)?;
 
-        // get listeners by id
-        unique_ids
+                Some(ListenerBacklog {
+                    listener_id: *id,
+                    listener: listener.clone(),
+                    pending_events: state.outbox.len(),
+                    consecutive_failures: state.consecutive_failures,
+                    last_error: state.last_error.clone(),
+                    disabled: state.disabled,
+                })
+            })
+            .collect();
+
+        backlog.sort_by_key(|it| it.listener_id);
+
+        backlog
+    }
+
+    pub fn get_matching_listeners(&self, event: &TokenMoveEvent) -> Vec<OnMoveListener> {
+        self.get_matching_listener_ids(event)
             .iter()
-            .map(|&id| self.enumeration.get(id).unwrap().clone())
+            .map(|id| self.enumeration.get(id).unwrap().clone())
             .collect()
     }
+
+    fn get_matching_listener_ids(&self, event: &TokenMoveEvent) -> Vec<u64> {
+        // the index only narrows the candidates down, the whole filter is checked afterwards
+        let from_ids = self.from_index.get(&event.from).into_iter().flatten();
+        let to_ids = self.to_index.get(&event.to).into_iter().flatten();
+
+        // dedup and keep the order of subscription
+        let candidates = from_ids
+            .chain(to_ids)
+            .chain(self.unindexed.iter())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        candidates
+            .into_iter()
+            .filter(|id| {
+                self.enumeration
+                    .get(id)
+                    .is_some_and(|listener| listener.filter.matches(event))
+            })
+            .collect()
+    }
+
+    fn index_mut(&mut self, key: IndexKey) -> &mut Vec<u64> {
+        match key {
+            IndexKey::From(account) => self.from_index.entry(account).or_default(),
+            IndexKey::To(account) => self.to_index.entry(account).or_default(),
+            IndexKey::Unindexed => &mut self.unindexed,
+        }
+    }
+}
+
+// a listener is indexed only by one of its account sets - any event it matches has to hit that set
+enum IndexKey {
+    From(Account),
+    To(Account),
+    Unindexed,
+}
+
+impl IndexKey {
+    fn of(filter: &Filter) -> Vec<IndexKey> {
+        let unique = |set: &Vec<Account>| set.iter().cloned().collect::<HashSet<_>>();
+
+        match (&filter.from, &filter.to) {
+            (Some(from), _) => unique(from).into_iter().map(IndexKey::From).collect(),
+            (None, Some(to)) => unique(to).into_iter().map(IndexKey::To).collect(),
+            (None, None) => vec![IndexKey::Unindexed],
+        }
+    }
+}
+
+// the listeners layout before account sets, kinds and the outbox, frozen for the states saved by it
+pub type AccountFilterV1 = Option<Account>;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FilterV1 {
+    pub from: AccountFilterV1,
+    pub to: AccountFilterV1,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OnMoveListenerV1 {
+    pub filter: FilterV1,
+    pub endpoint: RemoteCallEndpoint,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct OnMoveListenersInfoV1 {
+    pub id_counter: u64,
+    pub enumeration: HashMap<u64, OnMoveListenerV1>,
+    pub index: HashMap<AccountFilterV1, Vec<u64>>,
+}
+
+impl OnMoveListenersInfoV1 {
+    // listeners keep their ids, so their subscribers can still unsubscribe them
+    pub fn into_latest(self) -> OnMoveListenersInfo {
+        let mut enumeration: Vec<_> = self.enumeration.into_iter().collect();
+        enumeration.sort_by_key(|(id, _)| *id);
+
+        let mut listeners = OnMoveListenersInfo::default();
+        for (id, listener) in enumeration {
+            let filter = Filter {
+                from: listener.filter.from.map(|account| vec![account]),
+                to: listener.filter.to.map(|account| vec![account]),
+                kinds: None,
+                min_qty: None,
+                max_qty: None,
+            };
+
+            listeners.insert_listener(
+                id,
+                OnMoveListener {
+                    filter,
+                    endpoint: listener.endpoint,
+                },
+            );
+        }
+        listeners.id_counter = self.id_counter;
+
+        listeners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_cdk::export::candid::{decode_one, encode_one};
+
+    use crate::testing::{
+        any_move, listener, move_event, principal, stable_round_trip, v1_listeners,
+    };
+
+    use super::*;
+
+    fn unfiltered_listener(method_name: &str) -> OnMoveListener {
+        listener(any_move(), principal(4), method_name)
+    }
+
+    fn mint(seq: u64, qty: u128) -> TokenMoveEvent {
+        move_event(seq, None, Some(principal(1)), qty)
+    }
+
+    fn report(listener_id: u64, delivered: usize, error: Option<&str>) -> DeliveryReport {
+        DeliveryReport {
+            listener_id,
+            delivered,
+            error: error.map(String::from),
+        }
+    }
+
+    #[test]
+    fn payloads_with_textual_args_are_still_accepted() {
+        // the layout callers used before RemoteCallArgs was introduced
+        #[derive(CandidType)]
+        struct TextualPayload {
+            endpoint: RemoteCallEndpoint,
+            idl_str_args: String,
+            payment: u64,
+        }
+
+        let bytes = encode_one(TextualPayload {
+            endpoint: RemoteCallEndpoint {
+                canister_id: principal(1),
+                method_name: String::from("transfer"),
+            },
+            idl_str_args: String::from("(42 : nat64)"),
+            payment: 0,
+        })
+        .unwrap();
+        let mut payload = decode_one::<RemoteCallPayload>(&bytes).unwrap();
+
+        assert!(matches!(
+            payload.call_args(),
+            Ok(RemoteCallArgs::CandidString(args)) if args == "(42 : nat64)"
+        ));
+
+        payload.args = Some(RemoteCallArgs::Template(String::from("(${0.0})")));
+        assert!(matches!(
+            payload.call_args(),
+            Err(RemoteCallError::ConflictingArgs)
+        ));
+
+        payload.idl_str_args = None;
+        payload.args = None;
+        assert!(matches!(
+            payload.call_args(),
+            Err(RemoteCallError::MissingArgs)
+        ));
+    }
+
+    #[test]
+    fn listeners_match_only_if_every_criteria_is_met() {
+        let a = Some(principal(1));
+        let b = Some(principal(2));
+        let c = Some(principal(3));
+        let on_move = |from: AccountFilter, to: AccountFilter, kinds, min_qty| {
+            let filter = Filter {
+                from,
+                to,
+                kinds,
+                min_qty,
+                max_qty: None,
+            };
+
+            listener(filter, principal(4), "on_move")
+        };
+        let event = |from, to, qty| move_event(0, from, to, qty);
+
+        let mut listeners = OnMoveListenersInfo::default();
+        let a_to_b = listeners
+            .add_listener(on_move(Some(vec![a]), Some(vec![b]), None, None))
+            .unwrap();
+        let big_to_b_or_c = listeners
+            .add_listener(on_move(None, Some(vec![b, c]), None, Some(100)))
+            .unwrap();
+        let mints = listeners
+            .add_listener(on_move(None, None, Some(vec![TokenMoveKind::Mint]), None))
+            .unwrap();
+
+        assert_eq!(
+            listeners.get_matching_listener_ids(&event(a, b, 10)),
+            vec![a_to_b]
+        );
+        assert_eq!(
+            listeners.get_matching_listener_ids(&event(a, c, 10)),
+            Vec::<u64>::new()
+        );
+        assert_eq!(
+            listeners.get_matching_listener_ids(&event(a, c, 100)),
+            vec![big_to_b_or_c]
+        );
+        assert_eq!(
+            listeners.get_matching_listener_ids(&event(None, b, 500)),
+            vec![big_to_b_or_c, mints]
+        );
+
+        listeners.remove_listener(big_to_b_or_c).unwrap();
+        assert_eq!(
+            listeners.get_matching_listener_ids(&event(None, b, 500)),
+            vec![mints]
+        );
+
+        let empty_set = listeners.add_listener(on_move(Some(vec![]), None, None, None));
+        assert!(matches!(empty_set, Err(OnMoveListenerError::InvalidFilter)));
+    }
+
+    #[test]
+    fn events_are_enqueued_only_for_matching_listeners() {
+        let mut listeners = OnMoveListenersInfo::default();
+        let all = listeners
+            .add_listener(unfiltered_listener("on_move"))
+            .unwrap();
+
+        let mut big = unfiltered_listener("on_big_move");
+        big.filter.min_qty = Some(100);
+        let big = listeners.add_listener(big).unwrap();
+
+        listeners.enqueue_event(mint(0, 10));
+        listeners.enqueue_event(mint(1, 100));
+
+        let mut deliveries = listeners.take_pending_deliveries(0);
+        deliveries.sort_by_key(|d| d.listener_id);
+
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!(deliveries[0].listener_id, all);
+        assert_eq!(deliveries[0].events.len(), 2);
+        assert_eq!(deliveries[1].listener_id, big);
+        assert_eq!(deliveries[1].events[0].seq, 1);
+        assert_eq!(deliveries[1].endpoint.method_name, "on_big_move");
+    }
+
+    #[test]
+    fn delivered_events_leave_the_outbox() {
+        let mut listeners = OnMoveListenersInfo::default();
+        let id = listeners
+            .add_listener(unfiltered_listener("on_move"))
+            .unwrap();
+
+        for seq in 0..(MAX_DELIVERY_BATCH as u64 + 5) {
+            listeners.enqueue_event(mint(seq, 1));
+        }
+
+        let deliveries = listeners.take_pending_deliveries(0);
+        assert_eq!(deliveries[0].events.len(), MAX_DELIVERY_BATCH);
+
+        // the delivery stopped at the fourth event, the rest are sent the next time
+        listeners.complete_delivery(report(id, 3, Some("rejected")), 0);
+        let backlog = listeners.get_backlog();
+        assert_eq!(backlog[0].pending_events, MAX_DELIVERY_BATCH + 2);
+        assert_eq!(backlog[0].consecutive_failures, 1);
+        assert_eq!(backlog[0].last_error, Some(String::from("rejected")));
+
+        // the failed delivery is retried after a while
+        assert!(listeners.take_pending_deliveries(0).is_empty());
+        let now = DELIVERY_RETRY_INTERVAL;
+        let deliveries = listeners.take_pending_deliveries(now);
+        assert_eq!(deliveries[0].events[0].seq, 3);
+
+        listeners.complete_delivery(report(id, MAX_DELIVERY_BATCH, None), now);
+        assert_eq!(listeners.get_backlog()[0].consecutive_failures, 0);
+
+        listeners.take_pending_deliveries(now);
+        listeners.complete_delivery(report(id, 2, None), now);
+        assert!(listeners.take_pending_deliveries(now).is_empty());
+
+        // a report for a listener removed in the meantime is ignored
+        listeners.remove_listener(id).unwrap();
+        listeners.complete_delivery(report(id, 1, None), 0);
+        assert!(listeners.get_backlog().is_empty());
+    }
+
+    #[test]
+    fn events_beyond_the_outbox_limit_are_dropped() {
+        let mut listeners = OnMoveListenersInfo::default();
+        let id = listeners
+            .add_listener(unfiltered_listener("on_move"))
+            .unwrap();
+
+        for seq in 0..(MAX_OUTBOX_LENGTH as u64 + 2) {
+            listeners.enqueue_event(mint(seq, 1));
+        }
+        assert_eq!(listeners.get_backlog()[0].pending_events, MAX_OUTBOX_LENGTH);
+
+        // the outbox is delivered, the next event shows that two were missed
+        while let Some(delivery) = listeners.take_pending_deliveries(0).pop() {
+            listeners.complete_delivery(report(id, delivery.events.len(), None), 0);
+        }
+        listeners.enqueue_event(mint(MAX_OUTBOX_LENGTH as u64 + 2, 1));
+
+        let deliveries = listeners.take_pending_deliveries(0);
+        assert_eq!(
+            deliveries[0].events[0].prev_seq,
+            Some(MAX_OUTBOX_LENGTH as u64 + 1)
+        );
+    }
+
+    #[test]
+    fn events_in_flight_are_not_sent_twice() {
+        let mut listeners = OnMoveListenersInfo::default();
+        let id = listeners
+            .add_listener(unfiltered_listener("on_move"))
+            .unwrap();
+
+        listeners.enqueue_event(mint(0, 1));
+        assert_eq!(listeners.take_pending_deliveries(0).len(), 1);
+
+        // new events wait for the delivery in flight to complete
+        listeners.enqueue_event(mint(1, 1));
+        assert!(listeners.take_pending_deliveries(0).is_empty());
+
+        listeners.complete_delivery(report(id, 1, None), 0);
+        let deliveries = listeners.take_pending_deliveries(0);
+        assert_eq!(deliveries[0].events.len(), 1);
+        assert_eq!(deliveries[0].events[0].seq, 1);
+
+        // an upgrade interrupted the delivery, so it never completes
+        listeners.reset_in_flight_deliveries();
+        let deliveries = listeners.take_pending_deliveries(0);
+        assert_eq!(deliveries[0].events[0].seq, 1);
+    }
+
+    #[test]
+    fn failing_listeners_are_disabled_until_enabled_back() {
+        let mut listeners = OnMoveListenersInfo::default();
+        let id = listeners
+            .add_listener(unfiltered_listener("on_move"))
+            .unwrap();
+
+        listeners.enqueue_event(mint(0, 1));
+
+        let mut now = 0;
+        for _ in 0..MAX_DELIVERY_FAILURES {
+            assert_eq!(listeners.take_pending_deliveries(now).len(), 1);
+            listeners.complete_delivery(report(id, 0, Some("trapped")), now);
+            now = listeners.deliveries[&id].retry_at;
+        }
+
+        // the backlog of a disabled listener is dropped and it doesn't collect a new one
+        let backlog = listeners.get_backlog();
+        assert!(backlog[0].disabled);
+        assert_eq!(backlog[0].pending_events, 0);
+        assert!(listeners.take_pending_deliveries(now).is_empty());
+
+        listeners.enqueue_event(mint(1, 1));
+        assert_eq!(listeners.get_backlog()[0].pending_events, 0);
+
+        // once enabled, the listener learns from prev_seq that it has to catch up
+        listeners.enable_listener(id).unwrap();
+        listeners.enqueue_event(mint(2, 1));
+        let deliveries = listeners.take_pending_deliveries(0);
+        assert_eq!(deliveries[0].events.len(), 1);
+        assert_eq!(deliveries[0].events[0].prev_seq, Some(1));
+        assert!(matches!(
+            listeners.enable_listener(id + 1),
+            Err(OnMoveListenerError::ListenerDoesNotExist)
+        ));
+    }
+
+    #[test]
+    fn filtered_out_events_are_not_gaps() {
+        let emitter = principal(5);
+        let mut listeners = OnMoveListenersInfo::default();
+
+        let mut big = unfiltered_listener("on_big_move");
+        big.filter.min_qty = Some(100);
+        let big = listeners.add_listener(big).unwrap();
+
+        let mut log = TokenMoveEventLog::default();
+        for qty in [1, 100, 1, 1, 100] {
+            let event = log.emit(None, Some(principal(1)), qty, None, 0);
+            listeners.enqueue_event(event);
+        }
+
+        let events = listeners.take_pending_deliveries(0).remove(0).events;
+        assert_eq!(listeners.deliveries[&big].last_enqueued_seq, Some(4));
+
+        // the very first event still tells that the listener has missed the earlier history
+        let mut tracker = EventSequenceTracker::default();
+        assert_eq!(
+            tracker.check(&emitter, &events[0]),
+            SequenceCheck::Gap {
+                from_seq: 0,
+                to_seq: 1
+            }
+        );
+
+        for event in log.get_events(0, 2).iter() {
+            assert_eq!(tracker.check(&emitter, event), SequenceCheck::InOrder);
+            tracker.mark_applied(emitter, event);
+        }
+
+        assert_eq!(
+            tracker.check(&emitter, &events[0]),
+            SequenceCheck::AlreadyApplied
+        );
+        assert_eq!(tracker.check(&emitter, &events[1]), SequenceCheck::InOrder);
+        tracker.mark_applied(emitter, &events[1]);
+
+        // an event of the emitter's log can't skip anything
+        let next = log.emit(None, None, 1, None, 0);
+        assert_eq!(tracker.check(&emitter, &next), SequenceCheck::InOrder);
+
+        let mut lost = next;
+        lost.seq = 7;
+        lost.prev_seq = Some(6);
+        assert_eq!(
+            tracker.check(&emitter, &lost),
+            SequenceCheck::Gap {
+                from_seq: 5,
+                to_seq: 7
+            }
+        );
+    }
+
+    #[test]
+    fn the_event_log_keeps_only_the_latest_events() {
+        let mut log = TokenMoveEventLog::default();
+        for _ in 0..(MAX_EVENT_LOG_LENGTH + 3) {
+            log.emit(None, None, 1, None, 0);
+        }
+
+        assert_eq!(log.events.len(), MAX_EVENT_LOG_LENGTH);
+        assert_eq!(log.first_seq, 3);
+        assert_eq!(log.next_seq(), MAX_EVENT_LOG_LENGTH as u64 + 3);
+
+        let page = log.get_events(5, 1000);
+        assert_eq!(page.len(), MAX_EVENTS_PAGE as usize);
+        assert_eq!(page[0].seq, 5);
+
+        // a pruned seq is answered with the oldest kept events
+        let pruned = log.get_events(0, 2);
+        assert_eq!(pruned[0].seq, 3);
+        assert_eq!(pruned[0].prev_seq, Some(2));
+
+        assert!(log.get_events(log.next_seq(), 10).is_empty());
+    }
+
+    #[test]
+    fn v1_listeners_keep_their_ids() {
+        let a = Some(principal(1));
+        // the V1 listener subscribed under 1 is gone already
+        let mut v1 = v1_listeners(2, Some(None), None, principal(4));
+        let first = v1_listeners(0, None, Some(a), principal(4));
+        v1.enumeration.extend(first.enumeration);
+
+        let mut listeners = stable_round_trip(v1).into_latest();
+
+        let mint = move_event(0, None, a, 1);
+        assert_eq!(listeners.get_matching_listener_ids(&mint), vec![0, 2]);
+
+        listeners.remove_listener(2).unwrap();
+        assert_eq!(
+            listeners
+                .add_listener(unfiltered_listener("on_move"))
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn kinds_and_qty_bounds_narrow_the_filter_down() {
+        let a = Some(principal(1));
+        let b = Some(principal(2));
+        let event = |from, to, qty| move_event(0, from, to, qty);
+        let filter = |kinds, min_qty, max_qty| Filter {
+            from: None,
+            to: None,
+            kinds,
+            min_qty,
+            max_qty,
+        };
+
+        let burns_and_transfers = filter(
+            Some(vec![TokenMoveKind::Burn, TokenMoveKind::Transfer]),
+            None,
+            None,
+        );
+        assert!(!burns_and_transfers.matches(&event(None, a, 1)));
+        assert!(burns_and_transfers.matches(&event(a, None, 1)));
+        assert!(burns_and_transfers.matches(&event(a, b, 1)));
+
+        // both bounds are inclusive
+        let medium = filter(None, Some(10), Some(20));
+        assert!(!medium.matches(&event(a, b, 9)));
+        assert!(medium.matches(&event(a, b, 10)));
+        assert!(medium.matches(&event(a, b, 20)));
+        assert!(!medium.matches(&event(a, b, 21)));
+
+        assert!(filter(None, Some(20), Some(20)).is_valid());
+        assert!(!filter(None, Some(21), Some(20)).is_valid());
+        assert!(!filter(Some(vec![]), None, None).is_valid());
+    }
+
+    #[test]
+    fn unsubscribed_listeners_leave_the_index() {
+        let a = Some(principal(1));
+        let b = Some(principal(2));
+
+        let mut listeners = OnMoveListenersInfo::default();
+
+        let mut from_a_or_b = unfiltered_listener("on_move");
+        from_a_or_b.filter.from = Some(vec![a, b, a]);
+        let from_a_or_b = listeners.add_listener(from_a_or_b).unwrap();
+
+        let mut to_b = unfiltered_listener("on_move");
+        to_b.filter.to = Some(vec![b]);
+        let to_b = listeners.add_listener(to_b).unwrap();
+
+        let unfiltered = listeners
+            .add_listener(unfiltered_listener("on_move"))
+            .unwrap();
+
+        assert_eq!(listeners.from_index[&a], vec![from_a_or_b]);
+        assert_eq!(listeners.from_index[&b], vec![from_a_or_b]);
+        assert_eq!(listeners.to_index[&b], vec![to_b]);
+        assert_eq!(listeners.unindexed, vec![unfiltered]);
+
+        listeners.remove_listener(from_a_or_b).unwrap();
+        listeners.remove_listener(unfiltered).unwrap();
+
+        assert!(listeners.from_index.is_empty());
+        assert_eq!(listeners.to_index[&b], vec![to_b]);
+        assert!(listeners.unindexed.is_empty());
+        assert!(!listeners.deliveries.contains_key(&from_a_or_b));
+        assert!(matches!(
+            listeners.remove_listener(from_a_or_b),
+            Err(OnMoveListenerError::ListenerDoesNotExist)
+        ));
+
+        listeners.remove_listener(to_b).unwrap();
+        assert!(listeners.to_index.is_empty());
+    }
 }
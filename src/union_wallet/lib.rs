@@ -1,42 +1,577 @@
-use ic_cdk::export::candid::{CandidType, Deserialize};
+use std::collections::{HashMap, HashSet};
+
+use futures::future::join_all;
+use ic_cdk::api::call::{msg_cycles_accept, msg_cycles_available};
+use ic_cdk::api::{canister_balance, time};
+use ic_cdk::export::candid::parser::value::IDLValue;
 use ic_cdk::export::Principal;
-use ic_cdk_macros::{init, update};
+use ic_cdk::storage::{stable_restore, stable_save};
+use ic_cdk::{call, caller, id};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 
-use union_utils::fns::{log, only_by, remote_call};
-use union_utils::types::{RemoteCallPayload, RemoteCallResult, VotingId};
+use union_utils::fns::{log, only_by, remote_call, resolve_args};
+use union_utils::types::{
+    Account, Filter, OnMoveListener, RemoteCallEndpoint, RemoteCallPayload, RemoteCallResult,
+    TokenMoveEvent, VotingId,
+};
 
-/*
- type UnionCallPayload {
-   program : vec RemoteCallPayload;
-   voting_id : VotingId;
- }
-*/
-#[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct UnionCallPayload {
-    program: Vec<RemoteCallPayload>,
-    voting_id: VotingId,
-}
+use crate::utils::{
+    check_call_result_equals, is_duplicate_transfer, Assertion, AssertionFailure, BalanceSnapshot,
+    CallController, CompensationResult, CyclesSpending, Disbursement, Error, ExecutionLedger,
+    ExecutionRecord, FungibleTokenTransferEntry, PaymentStream, PaymentStreamParams,
+    PaymentStreams, ProgramExecutionResult, SpendingLimits, StepResult, TokenTreasuryReport,
+    Treasury, TreasuryLogEntry, UnionCallPayload, UnionWallet, VersionedUnionWallet,
+};
+
+mod utils;
 
-static mut CALL_CONTROLLER: Option<Principal> = None;
+static mut WALLET: Option<UnionWallet> = None;
 
 #[init]
 fn init(call_controller: Principal) {
     log("union_wallet<>.init()");
 
-    unsafe { CALL_CONTROLLER = Some(call_controller) }
+    let mut call_controllers = HashMap::new();
+    call_controllers.insert(call_controller, None);
+
+    unsafe {
+        WALLET = Some(UnionWallet {
+            call_controllers,
+            execution_ledger: ExecutionLedger::default(),
+            cycles_spending: CyclesSpending::default(),
+            treasury: Treasury::default(),
+            payment_streams: PaymentStreams::default(),
+        })
+    }
+}
+
+// the wallet upgrades itself by a program calling install_code of the management canister,
+// so the wallet has to be one of its own controllers
+#[pre_upgrade]
+fn pre_upgrade_hook() {
+    log("union_wallet.pre_upgrade()");
+
+    let wallet = unsafe { WALLET.take().unwrap() };
+
+    stable_save((VersionedUnionWallet::V2(wallet),))
+        .expect("Unable to save the wallet to stable memory");
+}
+
+#[post_upgrade]
+fn post_upgrade_hook() {
+    log("union_wallet.post_upgrade()");
+
+    let (versioned,): (VersionedUnionWallet,) =
+        stable_restore().expect("Unable to restore the wallet from stable memory");
+
+    let mut wallet = versioned.into_latest();
+    wallet.settle_interrupted_executions();
+    wallet.payment_streams.reset_in_flight_payments();
+
+    unsafe { WALLET = Some(wallet) }
+}
+
+#[query]
+fn call_controllers() -> Vec<CallController> {
+    log("union_wallet.call_controllers()");
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+
+    wallet.get_call_controllers()
+}
+
+// controllers can only be changed by the wallet itself, i.e. by a program executed via _union_call
+#[update]
+fn add_call_controller(
+    controller: Principal,
+    allowed_endpoints: Option<HashSet<RemoteCallEndpoint>>,
+) -> Result<(), Error> {
+    log("union_wallet.add_call_controller()");
+
+    only_by(Some(id()));
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+
+    wallet.add_call_controller(controller, allowed_endpoints)
+}
+
+#[update]
+fn remove_call_controller(controller: Principal) -> Result<(), Error> {
+    log("union_wallet.remove_call_controller()");
+
+    only_by(Some(id()));
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+
+    wallet.remove_call_controller(&controller)
+}
+
+#[query]
+fn cycles_balance() -> u64 {
+    log("union_wallet.cycles_balance()");
+
+    canister_balance()
+}
+
+#[update]
+fn receive_cycles() -> u64 {
+    log("union_wallet.receive_cycles()");
+
+    msg_cycles_accept(msg_cycles_available())
+}
+
+#[query]
+fn cycles_spending() -> CyclesSpending {
+    log("union_wallet.cycles_spending()");
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+
+    wallet.cycles_spending.clone()
+}
+
+#[update]
+fn update_spending_limits(limits: SpendingLimits) -> Result<(), Error> {
+    log("union_wallet.update_spending_limits()");
+
+    only_by(Some(id()));
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+    wallet.cycles_spending.limits = limits;
+
+    Ok(())
+}
+
+#[query]
+fn treasury_report() -> Vec<TokenTreasuryReport> {
+    log("union_wallet.treasury_report()");
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+
+    wallet.treasury.report()
+}
+
+#[query]
+fn treasury_log(
+    token: Principal,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<TreasuryLogEntry>, Error> {
+    log("union_wallet.treasury_log()");
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+
+    wallet.treasury.get_log(&token, offset, limit)
+}
+
+// the wallet subscribes itself, so it should be the on move controller of the token
+#[update]
+async fn track_token(token: Principal) -> Result<(), Error> {
+    log("union_wallet.track_token()");
+
+    only_by(Some(id()));
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+
+    // if the snapshot couldn't be fetched the last time, the token is already subscribed to
+    if !wallet.treasury.is_awaiting_snapshot(&token) {
+        wallet.treasury.track(token)?;
+
+        if let Err(e) = subscribe_treasury_listeners(token).await {
+            let wallet = unsafe { WALLET.as_mut().unwrap() };
+            wallet.treasury.untrack(&token)?;
+
+            return Err(e);
+        }
+    }
+
+    fetch_balance_snapshot(token).await
+}
+
+// events which come in the meantime are kept until it's known whether the snapshot includes them
+async fn fetch_balance_snapshot(token: Principal) -> Result<(), Error> {
+    let snapshot = call::<_, (BalanceSnapshot,)>(token, "balance_snapshot_of", (id(),))
+        .await
+        .map(|(s,)| s)
+        .map_err(|(_, e)| Error::TokenCallFailed(e))?;
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+    wallet
+        .treasury
+        .apply_snapshot(&token, &id(), snapshot, time())
+}
+
+async fn subscribe_treasury_listeners(token: Principal) -> Result<(), Error> {
+    let endpoint = RemoteCallEndpoint {
+        canister_id: id(),
+        method_name: String::from("handle_on_move"),
+    };
+    let listeners = vec![
+        OnMoveListener {
+            filter: Filter {
+                from: Some(vec![Account::Some(id())]),
+                to: None,
+                kinds: None,
+                min_qty: None,
+                max_qty: None,
+            },
+            endpoint: endpoint.clone(),
+        },
+        OnMoveListener {
+            filter: Filter {
+                from: None,
+                to: Some(vec![Account::Some(id())]),
+                kinds: None,
+                min_qty: None,
+                max_qty: None,
+            },
+            endpoint,
+        },
+    ];
+
+    let (results,) =
+        call::<_, (Vec<Result<u64, IDLValue>>,)>(token, "subscribe_on_move", (listeners,))
+            .await
+            .map_err(|(_, e)| Error::TokenCallFailed(e))?;
+
+    let listener_ids = results
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::TokenCallFailed(e.to_string()))?;
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+    wallet.treasury.get_token_mut(&token)?.listener_ids = listener_ids;
+
+    Ok(())
+}
+
+// the token is forgotten only once it stops sending the events, so no listener is left behind
+#[update]
+async fn untrack_token(token: Principal) -> Result<(), Error> {
+    log("union_wallet.untrack_token()");
+
+    only_by(Some(id()));
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+    let listener_ids = wallet.treasury.get_token(&token)?.listener_ids.clone();
+
+    let (results,) = call::<_, (Vec<Result<OnMoveListener, IDLValue>>,)>(
+        token,
+        "unsubscribe_on_move",
+        (listener_ids.clone(),),
+    )
+    .await
+    .map_err(|(_, e)| Error::TokenCallFailed(e))?;
+
+    // the listeners which are still subscribed are kept, so untracking can be retried for them
+    let failed_ids: Vec<u64> = listener_ids
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| !matches!(results.get(*idx), Some(Ok(_))))
+        .map(|(_, listener_id)| listener_id)
+        .collect();
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+
+    if !failed_ids.is_empty() {
+        let error = format!("Listeners {:?} are not unsubscribed", failed_ids);
+        wallet.treasury.get_token_mut(&token)?.listener_ids = failed_ids;
+
+        return Err(Error::TokenCallFailed(error));
+    }
+
+    wallet.treasury.untrack(&token)?;
+
+    Ok(())
 }
 
 #[update]
-async fn _union_call(payload: UnionCallPayload) -> Vec<RemoteCallResult> {
-    let call_controller = unsafe { CALL_CONTROLLER };
+async fn handle_on_move(event: TokenMoveEvent) -> Result<(), Error> {
+    log("union_wallet.handle_on_move()");
 
-    only_by(call_controller);
+    let token = caller();
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
 
-    let mut results: Vec<RemoteCallResult> = Vec::new();
+    wallet
+        .treasury
+        .handle_on_move(&token, &id(), &event, time())?;
 
-    for instruction in payload.program.into_iter() {
-        results.push(remote_call(instruction).await);
+    // the event is kept either way - if the snapshot can't be fetched now, the next event retries
+    if wallet.treasury.is_awaiting_snapshot(&token) {
+        if let Err(e) = fetch_balance_snapshot(token).await {
+            log(&format!("union_wallet.handle_on_move(): {:?}", e));
+        }
     }
 
-    results
+    Ok(())
+}
+
+#[query]
+fn get_payment_streams() -> Vec<PaymentStream> {
+    log("union_wallet.get_payment_streams()");
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+
+    wallet.payment_streams.get_streams()
+}
+
+#[query]
+fn get_disbursements(stream_id: Option<u64>, offset: usize, limit: usize) -> Vec<Disbursement> {
+    log("union_wallet.get_disbursements()");
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+
+    wallet
+        .payment_streams
+        .get_disbursements(stream_id, offset, limit)
+}
+
+#[update]
+fn create_payment_stream(params: PaymentStreamParams) -> Result<u64, Error> {
+    log("union_wallet.create_payment_stream()");
+
+    only_by(Some(id()));
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+
+    wallet.payment_streams.create(params)
+}
+
+#[update]
+fn cancel_payment_stream(stream_id: u64) -> Result<(), Error> {
+    log("union_wallet.cancel_payment_stream()");
+
+    only_by(Some(id()));
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+
+    wallet.payment_streams.cancel(stream_id, time())
+}
+
+// ic-cdk-macros has no heartbeat attribute yet, so the entry point is exported manually
+// and does the setup the macros would do
+#[export_name = "canister_heartbeat"]
+fn heartbeat() {
+    ic_cdk::setup();
+    ic_cdk::block_on(disburse_payment_streams());
+}
+
+async fn disburse_payment_streams() {
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+    let due = wallet.payment_streams.take_due_payments(time());
+
+    if due.is_empty() {
+        return;
+    }
+
+    log("union_wallet.disburse_payment_streams()");
+
+    let calls = due.into_iter().map(|mut disbursement| async move {
+        let entries = vec![FungibleTokenTransferEntry {
+            to: disbursement.recipient,
+            qty: disbursement.qty,
+            // lets the recipient tell which stream the payment belongs to
+            memo: Some(disbursement.stream_id.to_be_bytes().to_vec()),
+            created_at: disbursement.created_at(),
+        }];
+
+        let result =
+            call::<_, (Vec<Result<(), IDLValue>>,)>(disbursement.token, "send", (entries,)).await;
+
+        disbursement.result = match result {
+            Ok((results,)) => match results.into_iter().next() {
+                Some(Ok(())) => Ok(()),
+                Some(Err(e)) if is_duplicate_transfer(&e) => Ok(()),
+                Some(Err(e)) => Err(e.to_string()),
+                None => Err(String::from("Empty response")),
+            },
+            Err((_, e)) => Err(e),
+        };
+
+        disbursement
+    });
+
+    let disbursements = join_all(calls).await;
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+    for disbursement in disbursements {
+        wallet.payment_streams.record_disbursement(disbursement);
+    }
+}
+
+#[query]
+fn get_execution_record(voting_id: VotingId) -> Result<ExecutionRecord, Error> {
+    log("union_wallet.get_execution_record()");
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+
+    wallet
+        .execution_ledger
+        .get_record(&voting_id)
+        .cloned()
+        .ok_or(Error::ExecutionRecordDoesNotExist)
+}
+
+#[query]
+fn get_execution_records(offset: usize, limit: usize) -> Vec<ExecutionRecord> {
+    log("union_wallet.get_execution_records()");
+
+    let wallet = unsafe { WALLET.as_ref().unwrap() };
+
+    wallet.execution_ledger.get_records(offset, limit)
+}
+
+#[update]
+async fn _union_call(payload: UnionCallPayload) -> Result<ProgramExecutionResult, Error> {
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+
+    wallet.check_call_controller(&caller(), &payload)?;
+
+    let stages = payload.stages()?;
+    let voting_id = payload.voting_id.clone();
+
+    wallet.begin_execution(&payload, time())?;
+
+    // the world state may have changed since the voting, so the program only runs if it still matches
+    let (precondition, mut cycles_spent) = check_assertions(&payload.preconditions).await;
+    if let Err(failure) = precondition {
+        let wallet = unsafe { WALLET.as_mut().unwrap() };
+        wallet.abort_execution(&voting_id, cycles_spent)?;
+
+        return Err(Error::PreconditionFailed(failure));
+    }
+
+    let stops_on_error = payload.policy.stops_on_error();
+    let mut failed = false;
+    let mut steps: Vec<StepResult> = Vec::new();
+    let mut program = payload.program.into_iter();
+
+    for stage_size in stages {
+        let instructions: Vec<_> = program.by_ref().take(stage_size).collect();
+
+        if failed && stops_on_error {
+            steps.extend(instructions.iter().map(|_| StepResult::Skipped));
+            continue;
+        }
+
+        let payments: Vec<_> = instructions.iter().map(|i| i.payment).collect();
+
+        // steps of the same stage are independent, so they are dispatched concurrently
+        let results = join_all(
+            instructions
+                .into_iter()
+                .map(|instruction| resolve_and_call(instruction, &steps)),
+        )
+        .await;
+
+        for (result, payment) in results.into_iter().zip(payments) {
+            if result.is_ok() {
+                cycles_spent = cycles_spent.saturating_add(payment);
+            }
+
+            let step = StepResult::Executed(result);
+            failed |= step.is_failed();
+
+            steps.push(step);
+        }
+    }
+
+    let mut postcondition_failure = None;
+
+    // postconditions make no sense for a program that already failed
+    if !failed {
+        let (postcondition, spent) = check_assertions(&payload.postconditions).await;
+        cycles_spent = cycles_spent.saturating_add(spent);
+
+        if let Err(failure) = postcondition {
+            postcondition_failure = Some(failure);
+            failed = true;
+        }
+    }
+
+    let mut compensations: Vec<CompensationResult> = Vec::new();
+
+    if failed {
+        for compensation in payload.policy.compensations_to_fire(&steps) {
+            let payment = compensation.call.payment;
+            let result = resolve_and_call(compensation.call, &steps).await;
+
+            if result.is_ok() {
+                cycles_spent = cycles_spent.saturating_add(payment);
+            }
+
+            compensations.push(CompensationResult {
+                step: compensation.step,
+                result,
+            });
+        }
+    }
+
+    let result = ProgramExecutionResult {
+        steps,
+        compensations,
+        postcondition_failure: postcondition_failure.clone(),
+    };
+
+    let wallet = unsafe { WALLET.as_mut().unwrap() };
+    wallet.finish_execution(&voting_id, result.clone(), cycles_spent)?;
+
+    match postcondition_failure {
+        Some(failure) => Err(Error::PostconditionFailed(failure)),
+        None => Ok(result),
+    }
+}
+
+// assertions are checked one by one, the first failed one is returned;
+// cycles attached to rejected calls are refunded, so only successful calls are counted as spent
+async fn check_assertions(assertions: &[Assertion]) -> (Result<(), AssertionFailure>, u64) {
+    let mut cycles_spent = 0u64;
+
+    for (index, assertion) in assertions.iter().enumerate() {
+        let check = match assertion {
+            Assertion::CallResultEquals { call, expected } => {
+                let payment = call.payment;
+                let result = remote_call(call.clone()).await;
+
+                if result.is_ok() {
+                    cycles_spent = cycles_spent.saturating_add(payment);
+                }
+
+                check_call_result_equals(&result, expected)
+            }
+            Assertion::CyclesBalanceAtLeast(qty) => {
+                let balance = canister_balance();
+
+                if balance >= *qty {
+                    Ok(())
+                } else {
+                    Err(format!("Cycles balance {} is less than {}", balance, qty))
+                }
+            }
+            Assertion::TokenBalanceAtLeast { token, qty } => {
+                match call::<_, (u128,)>(*token, "balance_of", (id(),)).await {
+                    Ok((balance,)) if balance >= *qty => Ok(()),
+                    Ok((balance,)) => {
+                        Err(format!("Token balance {} is less than {}", balance, qty))
+                    }
+                    Err((_, e)) => Err(e),
+                }
+            }
+        };
+
+        if let Err(reason) = check {
+            return (Err(AssertionFailure { index, reason }), cycles_spent);
+        }
+    }
+
+    (Ok(()), cycles_spent)
+}
+
+// template args are resolved against results of the steps executed so far
+async fn resolve_and_call(mut entry: RemoteCallPayload, steps: &[StepResult]) -> RemoteCallResult {
+    let results: Vec<_> = steps.iter().map(StepResult::result).collect();
+
+    entry.args = Some(resolve_args(entry.call_args()?, &results)?);
+    entry.idl_str_args = None;
+
+    remote_call(entry).await
 }
@@ -0,0 +1,1889 @@
+use std::collections::{HashMap, HashSet};
+
+use ic_cdk::export::candid::parser::value::IDLValue;
+use ic_cdk::export::candid::{encode_one, idl_hash, CandidType, Deserialize, IDLArgs, Principal};
+use sha2::{Digest, Sha256};
+
+use union_utils::fns::serialize_args;
+use union_utils::types::{
+    Account, RemoteCallArgs, RemoteCallEndpoint, RemoteCallPayload, RemoteCallResult,
+    TokenMoveEvent, VotingId,
+};
+
+/*
+ type Compensation = record {
+   step : nat64;
+   call : RemoteCallPayload;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Compensation {
+    pub step: usize,
+    pub call: RemoteCallPayload,
+}
+
+/*
+ type ExecutionPolicy = variant {
+   Continue;
+   StopOnFirstError;
+   Compensate : vec Compensation;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ExecutionPolicy {
+    Continue,
+    StopOnFirstError,
+    Compensate(Vec<Compensation>),
+}
+
+impl ExecutionPolicy {
+    pub fn stops_on_error(&self) -> bool {
+        !matches!(self, ExecutionPolicy::Continue)
+    }
+
+    // compensations of successfully executed steps are fired in reverse order
+    pub fn compensations_to_fire(&self, steps: &[StepResult]) -> Vec<Compensation> {
+        let compensations = match self {
+            ExecutionPolicy::Compensate(c) => c,
+            _ => return Vec::new(),
+        };
+
+        let mut to_fire: Vec<_> = compensations
+            .iter()
+            .filter(|c| matches!(steps.get(c.step), Some(StepResult::Executed(Ok(_)))))
+            .cloned()
+            .collect();
+
+        to_fire.sort_by_key(|c| std::cmp::Reverse(c.step));
+
+        to_fire
+    }
+}
+
+/*
+ type StepRange = record {
+   start : nat64;
+   end : nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StepRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+/*
+ type Assertion = variant {
+   CallResultEquals : record { call : RemoteCallPayload; expected : RemoteCallArgs; };
+   CyclesBalanceAtLeast : nat64;
+   TokenBalanceAtLeast : record { token : principal; qty : nat; };
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum Assertion {
+    // expected values should be type-annotated, e.g. "(100 : nat64)", since they are compared
+    // with the decoded result value by value
+    CallResultEquals {
+        call: RemoteCallPayload,
+        expected: RemoteCallArgs,
+    },
+    CyclesBalanceAtLeast(u64),
+    TokenBalanceAtLeast {
+        token: Principal,
+        qty: u128,
+    },
+}
+
+impl Assertion {
+    pub fn call(&self) -> Option<&RemoteCallPayload> {
+        match self {
+            Assertion::CallResultEquals { call, .. } => Some(call),
+            _ => None,
+        }
+    }
+}
+
+pub fn check_call_result_equals(
+    result: &RemoteCallResult,
+    expected: &RemoteCallArgs,
+) -> Result<(), String> {
+    let raw = result.as_ref().map_err(|e| format!("{:?}", e))?;
+    let actual = IDLArgs::from_bytes(raw).map_err(|e| e.to_string())?;
+
+    let (_, expected_raw) = serialize_args(expected).map_err(|e| format!("{:?}", e))?;
+    let expected = IDLArgs::from_bytes(&expected_raw).map_err(|e| e.to_string())?;
+
+    if actual.args != expected.args {
+        return Err(format!("Expected {}, got {}", expected, actual));
+    }
+
+    Ok(())
+}
+
+/*
+ type AssertionFailure = record {
+   index : nat64;
+   reason : text;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AssertionFailure {
+    pub index: usize,
+    pub reason: String,
+}
+
+/*
+ type UnionCallPayload = record {
+   program : vec RemoteCallPayload;
+   parallel_groups : vec StepRange;
+   policy : ExecutionPolicy;
+   preconditions : vec Assertion;
+   postconditions : vec Assertion;
+   voting_id : VotingId;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UnionCallPayload {
+    pub program: Vec<RemoteCallPayload>,
+    pub parallel_groups: Vec<StepRange>,
+    pub policy: ExecutionPolicy,
+    pub preconditions: Vec<Assertion>,
+    pub postconditions: Vec<Assertion>,
+    pub voting_id: VotingId,
+}
+
+impl UnionCallPayload {
+    // splits the program into consecutive stages - parallel groups become one stage,
+    // every other step is a stage of its own; returns the size of each stage
+    pub fn stages(&self) -> Result<Vec<usize>, Error> {
+        let mut groups = self.parallel_groups.clone();
+        groups.sort_by_key(|g| g.start);
+
+        let mut stages = Vec::new();
+        let mut next_step = 0;
+
+        for group in groups.iter() {
+            if group.start < next_step || group.start >= group.end || group.end > self.program.len()
+            {
+                return Err(Error::InvalidParallelGroups);
+            }
+
+            stages.extend((next_step..group.start).map(|_| 1));
+            stages.push(group.end - group.start);
+
+            next_step = group.end;
+        }
+
+        stages.extend((next_step..self.program.len()).map(|_| 1));
+
+        Ok(stages)
+    }
+
+    pub fn endpoints(&self) -> impl Iterator<Item = &RemoteCallEndpoint> {
+        let compensations = match &self.policy {
+            ExecutionPolicy::Compensate(c) => c.as_slice(),
+            _ => &[],
+        };
+
+        self.program
+            .iter()
+            .chain(compensations.iter().map(|c| &c.call))
+            .chain(self.assertion_calls())
+            .map(|entry| &entry.endpoint)
+    }
+
+    pub fn assertion_calls(&self) -> impl Iterator<Item = &RemoteCallPayload> {
+        self.preconditions
+            .iter()
+            .chain(self.postconditions.iter())
+            .filter_map(Assertion::call)
+    }
+
+    pub fn total_payment(&self) -> u64 {
+        let compensations = match &self.policy {
+            ExecutionPolicy::Compensate(c) => c.as_slice(),
+            _ => &[],
+        };
+
+        self.program
+            .iter()
+            .chain(compensations.iter().map(|c| &c.call))
+            .chain(self.assertion_calls())
+            .fold(0u64, |sum, entry| sum.saturating_add(entry.payment))
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        let bytes = encode_one(self).expect("Unable to encode the payload");
+
+        Sha256::digest(bytes.as_slice()).to_vec()
+    }
+}
+
+/*
+ type Error = variant {
+   InvalidParallelGroups;
+   VotingAlreadyExecuted;
+   ExecutionRecordDoesNotExist;
+   AccessDenied;
+   EndpointIsNotAllowed : RemoteCallEndpoint;
+   LastCallControllerCannotBeRemoved;
+   CallControllerDoesNotExist;
+   VotingSpendingLimitExceeded;
+   PeriodSpendingLimitExceeded;
+   TokenAlreadyTracked;
+   TokenIsNotTracked;
+   TokenCallFailed : text;
+   InvalidPaymentStream;
+   PaymentStreamDoesNotExist;
+   PaymentStreamIsNotActive;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum Error {
+    InvalidParallelGroups,
+    VotingAlreadyExecuted,
+    ExecutionRecordDoesNotExist,
+    AccessDenied,
+    EndpointIsNotAllowed(RemoteCallEndpoint),
+    LastCallControllerCannotBeRemoved,
+    CallControllerDoesNotExist,
+    VotingSpendingLimitExceeded,
+    PeriodSpendingLimitExceeded,
+    TokenAlreadyTracked,
+    TokenIsNotTracked,
+    TokenCallFailed(String),
+    InvalidPaymentStream,
+    PaymentStreamDoesNotExist,
+    PaymentStreamIsNotActive,
+    PreconditionFailed(AssertionFailure),
+    PostconditionFailed(AssertionFailure),
+}
+
+/*
+ type StepResult = variant {
+   Executed : RemoteCallResult;
+   Skipped;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum StepResult {
+    Executed(RemoteCallResult),
+    Skipped,
+}
+
+impl StepResult {
+    pub fn is_failed(&self) -> bool {
+        matches!(self, StepResult::Executed(Err(_)))
+    }
+
+    pub fn result(&self) -> Option<&RemoteCallResult> {
+        match self {
+            StepResult::Executed(r) => Some(r),
+            StepResult::Skipped => None,
+        }
+    }
+}
+
+/*
+ type CompensationResult = record {
+   step : nat64;
+   result : RemoteCallResult;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CompensationResult {
+    pub step: usize,
+    pub result: RemoteCallResult,
+}
+
+/*
+ type ProgramExecutionResult = record {
+   steps : vec StepResult;
+   compensations : vec CompensationResult;
+   postcondition_failure : opt AssertionFailure;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ProgramExecutionResult {
+    pub steps: Vec<StepResult>,
+    pub compensations: Vec<CompensationResult>,
+    pub postcondition_failure: Option<AssertionFailure>,
+}
+
+/*
+ type ExecutionRecord = record {
+   voting_id : VotingId;
+   executed_at : nat64;
+   payload_hash : blob;
+   cycles_reserved : nat64;
+   cycles_spent : nat64;
+   result : opt ProgramExecutionResult;
+   interrupted_by_upgrade : bool;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ExecutionRecord {
+    pub voting_id: VotingId,
+    pub executed_at: u64,
+    pub payload_hash: Vec<u8>,
+    pub cycles_reserved: u64,
+    pub cycles_spent: u64,
+    // None while the program is still being executed
+    pub result: Option<ProgramExecutionResult>,
+    // responses to the calls that were in flight during an upgrade are never delivered,
+    // e.g. the install_code call of the program that upgrades the wallet itself
+    pub interrupted_by_upgrade: bool,
+}
+
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct ExecutionLedger {
+    pub records: Vec<ExecutionRecord>,
+    pub index: HashMap<VotingId, usize>,
+}
+
+impl ExecutionLedger {
+    // the record is created before the program runs, so a concurrent call with the same voting is rejected
+    pub fn begin_execution(
+        &mut self,
+        voting_id: VotingId,
+        payload_hash: Vec<u8>,
+        cycles_reserved: u64,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        if self.index.contains_key(&voting_id) {
+            return Err(Error::VotingAlreadyExecuted);
+        }
+
+        self.index.insert(voting_id.clone(), self.records.len());
+        self.records.push(ExecutionRecord {
+            voting_id,
+            executed_at: timestamp,
+            payload_hash,
+            cycles_reserved,
+            cycles_spent: 0,
+            result: None,
+            interrupted_by_upgrade: false,
+        });
+
+        Ok(())
+    }
+
+    pub fn finish_execution(
+        &mut self,
+        voting_id: &VotingId,
+        result: ProgramExecutionResult,
+        cycles_spent: u64,
+    ) -> Result<&ExecutionRecord, Error> {
+        let idx = self
+            .index
+            .get(voting_id)
+            .ok_or(Error::ExecutionRecordDoesNotExist)?;
+
+        let record = &mut self.records[*idx];
+        record.result = Some(result);
+        record.cycles_spent = cycles_spent;
+
+        Ok(record)
+    }
+
+    // removes the record of an execution that didn't start, so the voting can be executed later
+    pub fn abort_execution(&mut self, voting_id: &VotingId) -> Result<ExecutionRecord, Error> {
+        let idx = self
+            .index
+            .remove(voting_id)
+            .ok_or(Error::ExecutionRecordDoesNotExist)?;
+
+        let record = self.records.remove(idx);
+        for i in self.index.values_mut() {
+            if *i > idx {
+                *i -= 1;
+            }
+        }
+
+        Ok(record)
+    }
+
+    pub fn get_record(&self, voting_id: &VotingId) -> Option<&ExecutionRecord> {
+        self.index.get(voting_id).map(|idx| &self.records[*idx])
+    }
+
+    pub fn get_records(&self, offset: usize, limit: usize) -> Vec<ExecutionRecord> {
+        self.records
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+/*
+ type CallController = record {
+   controller : principal;
+   allowed_endpoints : opt vec RemoteCallEndpoint;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CallController {
+    pub controller: Principal,
+    pub allowed_endpoints: Option<HashSet<RemoteCallEndpoint>>,
+}
+
+/*
+ type PeriodSpendingLimit = record {
+   period : nat64;
+   limit : nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PeriodSpendingLimit {
+    pub period: u64,
+    pub limit: u64,
+}
+
+/*
+ type SpendingLimits = record {
+   per_voting : opt nat64;
+   per_period : opt PeriodSpendingLimit;
+ }
+*/
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct SpendingLimits {
+    pub per_voting: Option<u64>,
+    pub per_period: Option<PeriodSpendingLimit>,
+}
+
+/*
+ type CyclesSpending = record {
+   limits : SpendingLimits;
+   period_start : nat64;
+   period_spent : nat64;
+   total_spent : nat64;
+ }
+*/
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct CyclesSpending {
+    pub limits: SpendingLimits,
+    pub period_start: u64,
+    pub period_spent: u64,
+    pub total_spent: u64,
+}
+
+impl CyclesSpending {
+    // cycles are reserved before the execution, so concurrent programs can't exceed the limits together
+    pub fn reserve(&mut self, amount: u64, timestamp: u64) -> Result<(), Error> {
+        if let Some(per_voting) = self.limits.per_voting {
+            if amount > per_voting {
+                return Err(Error::VotingSpendingLimitExceeded);
+            }
+        }
+
+        if let Some(per_period) = &self.limits.per_period {
+            if timestamp >= self.period_start.saturating_add(per_period.period) {
+                self.period_start = timestamp;
+                self.period_spent = 0;
+            }
+
+            if self.period_spent.saturating_add(amount) > per_period.limit {
+                return Err(Error::PeriodSpendingLimitExceeded);
+            }
+        }
+
+        self.period_spent = self.period_spent.saturating_add(amount);
+
+        Ok(())
+    }
+
+    // cycles that were reserved, but not spent, are returned to the period they were reserved in
+    pub fn settle(&mut self, reserved: u64, spent: u64, reserved_at: u64) {
+        if reserved_at >= self.period_start {
+            self.period_spent = self
+                .period_spent
+                .saturating_sub(reserved.saturating_sub(spent));
+        }
+
+        self.total_spent = self.total_spent.saturating_add(spent);
+    }
+}
+
+/*
+ type TreasuryFlow = variant {
+   Inflow;
+   Outflow;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TreasuryFlow {
+    Inflow,
+    Outflow,
+}
+
+/*
+ type TreasuryLogEntry = record {
+   flow : TreasuryFlow;
+   counterparty : Account;
+   qty : nat;
+   timestamp : nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TreasuryLogEntry {
+    pub flow: TreasuryFlow,
+    pub counterparty: Account,
+    pub qty: u128,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct TrackedToken {
+    pub listener_ids: Vec<u64>,
+    // token amounts are candid nat, as in fungible_token
+    pub balance: u128,
+    pub total_inflow: u128,
+    pub total_outflow: u128,
+    pub log: Vec<TreasuryLogEntry>,
+    // the balance snapshot includes every event below this seq; None until the snapshot arrives
+    pub synced_seq: Option<u64>,
+    // events which came before the snapshot - it's unknown yet whether they are included into it
+    pub pending_events: Vec<TokenMoveEvent>,
+    // the outflow and the inflow listeners chain their events separately, so each has its own
+    // next expected seq
+    pub next_outflow_seq: u64,
+    pub next_inflow_seq: u64,
+}
+
+impl TrackedToken {
+    // a transfer to self is delivered by both listeners - it goes to the chain which hasn't seen it
+    fn next_seq_mut(&mut self, wallet: &Principal, event: &TokenMoveEvent) -> Option<&mut u64> {
+        let mut chains = Vec::new();
+        if event.from == Some(*wallet) {
+            chains.push(&mut self.next_outflow_seq);
+        }
+        if event.to == Some(*wallet) {
+            chains.push(&mut self.next_inflow_seq);
+        }
+
+        chains.into_iter().find(|next_seq| event.seq >= **next_seq)
+    }
+
+    // false if some events of the listener are missing - they can't be applied then; the first
+    // event of a listener keeps the emitter's prev_seq, so it may take one more snapshot to settle
+    fn apply_in_order(
+        &mut self,
+        wallet: &Principal,
+        event: &TokenMoveEvent,
+        timestamp: u64,
+    ) -> bool {
+        let next_seq = match self.next_seq_mut(wallet, event) {
+            Some(next_seq) => next_seq,
+            // already applied or included into the snapshot
+            None => return true,
+        };
+
+        if event.prev_seq.is_some_and(|prev_seq| prev_seq >= *next_seq) {
+            return false;
+        }
+
+        *next_seq = event.seq + 1;
+        self.apply_event(wallet, event, timestamp);
+
+        true
+    }
+
+    fn apply_event(&mut self, wallet: &Principal, event: &TokenMoveEvent, timestamp: u64) {
+        let (flow, counterparty) = if event.to == Some(*wallet) && event.from != Some(*wallet) {
+            (TreasuryFlow::Inflow, event.from)
+        } else if event.from == Some(*wallet) && event.to != Some(*wallet) {
+            (TreasuryFlow::Outflow, event.to)
+        } else {
+            // events that don't move tokens in or out of the wallet
+            return;
+        };
+
+        match flow {
+            TreasuryFlow::Inflow => {
+                self.balance = self.balance.saturating_add(event.qty);
+                self.total_inflow = self.total_inflow.saturating_add(event.qty);
+            }
+            TreasuryFlow::Outflow => {
+                self.balance = self.balance.saturating_sub(event.qty);
+                self.total_outflow = self.total_outflow.saturating_add(event.qty);
+            }
+        };
+
+        self.log.push(TreasuryLogEntry {
+            flow,
+            counterparty,
+            qty: event.qty,
+            timestamp,
+        });
+    }
+}
+
+/*
+ type BalanceSnapshot = record {
+   balance : nat;
+   next_seq : nat64;
+ }
+*/
+// mirrors fungible_token's snapshot - the balance includes every event with a seq below next_seq
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BalanceSnapshot {
+    pub balance: u128,
+    pub next_seq: u64,
+}
+
+/*
+ type TokenTreasuryReport = record {
+   token : principal;
+   balance : nat;
+   total_inflow : nat;
+   total_outflow : nat;
+   log_length : nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TokenTreasuryReport {
+    pub token: Principal,
+    pub balance: u128,
+    pub total_inflow: u128,
+    pub total_outflow: u128,
+    pub log_length: usize,
+}
+
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct Treasury {
+    pub tokens: HashMap<Principal, TrackedToken>,
+}
+
+impl Treasury {
+    pub fn track(&mut self, token: Principal) -> Result<(), Error> {
+        if self.tokens.contains_key(&token) {
+            return Err(Error::TokenAlreadyTracked);
+        }
+
+        self.tokens.insert(token, TrackedToken::default());
+
+        Ok(())
+    }
+
+    pub fn untrack(&mut self, token: &Principal) -> Result<TrackedToken, Error> {
+        self.tokens.remove(token).ok_or(Error::TokenIsNotTracked)
+    }
+
+    pub fn get_token(&self, token: &Principal) -> Result<&TrackedToken, Error> {
+        self.tokens.get(token).ok_or(Error::TokenIsNotTracked)
+    }
+
+    pub fn get_token_mut(&mut self, token: &Principal) -> Result<&mut TrackedToken, Error> {
+        self.tokens.get_mut(token).ok_or(Error::TokenIsNotTracked)
+    }
+
+    // the token is subscribed to, but its balance snapshot couldn't be fetched yet or a gap in its
+    // events needs a fresh one
+    pub fn is_awaiting_snapshot(&self, token: &Principal) -> bool {
+        self.tokens
+            .get(token)
+            .is_some_and(|t| t.synced_seq.is_none() && !t.listener_ids.is_empty())
+    }
+
+    pub fn handle_on_move(
+        &mut self,
+        token: &Principal,
+        wallet: &Principal,
+        event: &TokenMoveEvent,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let tracked = self.get_token_mut(token)?;
+
+        match tracked.synced_seq {
+            None => tracked.pending_events.push(event.clone()),
+            Some(_) => {
+                // the flows of the missing events are lost, but a fresh snapshot brings the balance back
+                if !tracked.apply_in_order(wallet, event, timestamp) {
+                    tracked.synced_seq = None;
+                    tracked.pending_events.push(event.clone());
+                }
+            }
+        };
+
+        Ok(())
+    }
+
+    // flows are counted from the snapshot on, the earlier ones are only reflected in the balance
+    pub fn apply_snapshot(
+        &mut self,
+        token: &Principal,
+        wallet: &Principal,
+        snapshot: BalanceSnapshot,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let tracked = self.get_token_mut(token)?;
+
+        // another snapshot, fetched at the same time, is applied already
+        if tracked.synced_seq.is_some() {
+            return Ok(());
+        }
+
+        tracked.balance = snapshot.balance;
+        tracked.synced_seq = Some(snapshot.next_seq);
+        tracked.next_outflow_seq = snapshot.next_seq;
+        tracked.next_inflow_seq = snapshot.next_seq;
+
+        let mut pending_events = std::mem::take(&mut tracked.pending_events);
+        pending_events.sort_by_key(|e| e.seq);
+
+        // the events after a gap wait for the next snapshot
+        let gap_idx = pending_events
+            .iter()
+            .position(|event| !tracked.apply_in_order(wallet, event, timestamp));
+
+        if let Some(idx) = gap_idx {
+            tracked.synced_seq = None;
+            tracked.pending_events = pending_events.split_off(idx);
+        }
+
+        Ok(())
+    }
+
+    pub fn report(&self) -> Vec<TokenTreasuryReport> {
+        self.tokens
+            .iter()
+            .map(|(token, tracked)| TokenTreasuryReport {
+                token: *token,
+                balance: tracked.balance,
+                total_inflow: tracked.total_inflow,
+                total_outflow: tracked.total_outflow,
+                log_length: tracked.log.len(),
+            })
+            .collect()
+    }
+
+    pub fn get_log(
+        &self,
+        token: &Principal,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<TreasuryLogEntry>, Error> {
+        let tracked = self.tokens.get(token).ok_or(Error::TokenIsNotTracked)?;
+
+        Ok(tracked
+            .log
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+/*
+ type FungibleTokenTransferEntry = record {
+   to : principal;
+   qty : nat;
+   memo : opt blob;
+   created_at : opt nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FungibleTokenTransferEntry {
+    pub to: Principal,
+    pub qty: u128,
+    pub memo: Option<Vec<u8>>,
+    pub created_at: Option<u64>,
+}
+
+/*
+ type PaymentStreamParams = record {
+   recipient : principal;
+   token : principal;
+   qty : nat;
+   period : nat64;
+   start_at : nat64;
+   end_at : opt nat64;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PaymentStreamParams {
+    pub recipient: Principal,
+    pub token: Principal,
+    pub qty: u128,
+    pub period: u64,
+    pub start_at: u64,
+    pub end_at: Option<u64>,
+}
+
+/*
+ type PaymentStream = record {
+   id : nat64;
+   params : PaymentStreamParams;
+   next_payment_at : nat64;
+   cancelled_at : opt nat64;
+   payment_in_flight : bool;
+   failed_attempts : nat64;
+   last_error : opt text;
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PaymentStream {
+    pub id: u64,
+    pub params: PaymentStreamParams,
+    // the payment of this period is owed until it succeeds
+    pub next_payment_at: u64,
+    pub cancelled_at: Option<u64>,
+    pub payment_in_flight: bool,
+    // failed attempts to pay the current period
+    pub failed_attempts: u64,
+    pub last_error: Option<String>,
+}
+
+impl PaymentStream {
+    pub fn is_active(&self) -> bool {
+        if self.cancelled_at.is_some() {
+            return false;
+        }
+
+        match self.params.end_at {
+            Some(end_at) => self.next_payment_at <= end_at,
+            None => true,
+        }
+    }
+
+    pub fn is_due(&self, timestamp: u64) -> bool {
+        self.is_active() && !self.payment_in_flight && self.next_payment_at <= timestamp
+    }
+}
+
+// an hour less than fungible_token's deduplication window, so the token doesn't reject a payment
+// as too old while the call is on its way
+pub const TOKEN_TRANSACTION_WINDOW: u64 = 23 * 60 * 60 * 1_000_000_000;
+
+// the token already executed this very transfer - a retry of a payment whose reply was lost
+pub fn is_duplicate_transfer(error: &IDLValue) -> bool {
+    match error {
+        IDLValue::Variant(field, _) => field.id.get_id() == idl_hash("Duplicate"),
+        _ => false,
+    }
+}
+
+/*
+ type Disbursement = record {
+   stream_id : nat64;
+   scheduled_at : nat64;
+   executed_at : nat64;
+   recipient : principal;
+   token : principal;
+   qty : nat;
+   result : variant { Ok; Err : text; };
+ }
+*/
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Disbursement {
+    pub stream_id: u64,
+    pub scheduled_at: u64,
+    pub executed_at: u64,
+    pub recipient: Principal,
+    pub token: Principal,
+    pub qty: u128,
+    pub result: Result<(), String>,
+}
+
+impl Disbursement {
+    // every attempt to pay the same period carries the same created_at, so the token deduplicates
+    // retries; payments caught up later than the token's window can't be deduplicated
+    pub fn created_at(&self) -> Option<u64> {
+        if self.scheduled_at.saturating_add(TOKEN_TRANSACTION_WINDOW) >= self.executed_at {
+            Some(self.scheduled_at)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
+pub struct PaymentStreams {
+    pub id_counter: u64,
+    pub streams: HashMap<u64, PaymentStream>,
+    pub disbursements: Vec<Disbursement>,
+}
+
+impl PaymentStreams {
+    pub fn create(&mut self, params: PaymentStreamParams) -> Result<u64, Error> {
+        if params.qty == 0 || params.period == 0 {
+            return Err(Error::InvalidPaymentStream);
+        }
+
+        if let Some(end_at) = params.end_at {
+            if end_at < params.start_at {
+                return Err(Error::InvalidPaymentStream);
+            }
+        }
+
+        let id = self.id_counter;
+        self.id_counter += 1;
+
+        let stream = PaymentStream {
+            id,
+            next_payment_at: params.start_at,
+            params,
+            cancelled_at: None,
+            payment_in_flight: false,
+            failed_attempts: 0,
+            last_error: None,
+        };
+        self.streams.insert(id, stream);
+
+        Ok(id)
+    }
+
+    pub fn cancel(&mut self, id: u64, timestamp: u64) -> Result<(), Error> {
+        let stream = self
+            .streams
+            .get_mut(&id)
+            .ok_or(Error::PaymentStreamDoesNotExist)?;
+
+        if !stream.is_active() {
+            return Err(Error::PaymentStreamIsNotActive);
+        }
+
+        stream.cancelled_at = Some(timestamp);
+
+        Ok(())
+    }
+
+    // marks every due stream as paying, so the same payment is never picked twice; the period
+    // stays owed until record_disbursement settles it, and streams that are behind by several
+    // periods catch up one payment per call
+    pub fn take_due_payments(&mut self, timestamp: u64) -> Vec<Disbursement> {
+        self.streams
+            .values_mut()
+            .filter(|stream| stream.is_due(timestamp))
+            .map(|stream| {
+                stream.payment_in_flight = true;
+
+                Disbursement {
+                    stream_id: stream.id,
+                    scheduled_at: stream.next_payment_at,
+                    executed_at: timestamp,
+                    recipient: stream.params.recipient,
+                    token: stream.params.token,
+                    qty: stream.params.qty,
+                    result: Ok(()),
+                }
+            })
+            .collect()
+    }
+
+    // a failed payment is retried on the next heartbeat, only its first failure gets into the log
+    pub fn record_disbursement(&mut self, disbursement: Disbursement) {
+        if let Some(stream) = self.streams.get_mut(&disbursement.stream_id) {
+            stream.payment_in_flight = false;
+
+            match &disbursement.result {
+                Ok(()) => {
+                    if stream.next_payment_at == disbursement.scheduled_at {
+                        stream.next_payment_at = disbursement
+                            .scheduled_at
+                            .saturating_add(stream.params.period);
+                    }
+
+                    stream.failed_attempts = 0;
+                    stream.last_error = None;
+                }
+                Err(e) => {
+                    stream.failed_attempts += 1;
+                    stream.last_error = Some(e.clone());
+
+                    if stream.failed_attempts > 1 {
+                        return;
+                    }
+                }
+            };
+        }
+
+        self.disbursements.push(disbursement);
+    }
+
+    // payments interrupted by an upgrade never got their replies, they are retried
+    pub fn reset_in_flight_payments(&mut self) {
+        for stream in self.streams.values_mut() {
+            stream.payment_in_flight = false;
+        }
+    }
+
+    pub fn get_streams(&self) -> Vec<PaymentStream> {
+        let mut streams: Vec<_> = self.streams.values().cloned().collect();
+        streams.sort_by_key(|stream| stream.id);
+
+        streams
+    }
+
+    pub fn get_disbursements(
+        &self,
+        stream_id: Option<u64>,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<Disbursement> {
+        self.disbursements
+            .iter()
+            .filter(|it| stream_id.is_none_or(|id| it.stream_id == id))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UnionWallet {
+    // None means that the controller is allowed to call any endpoint
+    pub call_controllers: HashMap<Principal, Option<HashSet<RemoteCallEndpoint>>>,
+    pub execution_ledger: ExecutionLedger,
+    pub cycles_spending: CyclesSpending,
+    pub treasury: Treasury,
+    pub payment_streams: PaymentStreams,
+}
+
+impl UnionWallet {
+    pub fn begin_execution(
+        &mut self,
+        payload: &UnionCallPayload,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        if self
+            .execution_ledger
+            .get_record(&payload.voting_id)
+            .is_some()
+        {
+            return Err(Error::VotingAlreadyExecuted);
+        }
+
+        let cycles_reserved = payload.total_payment();
+        self.cycles_spending.reserve(cycles_reserved, timestamp)?;
+
+        self.execution_ledger.begin_execution(
+            payload.voting_id.clone(),
+            payload.hash(),
+            cycles_reserved,
+            timestamp,
+        )
+    }
+
+    pub fn finish_execution(
+        &mut self,
+        voting_id: &VotingId,
+        result: ProgramExecutionResult,
+        cycles_spent: u64,
+    ) -> Result<(), Error> {
+        let record = self
+            .execution_ledger
+            .finish_execution(voting_id, result, cycles_spent)?;
+
+        self.cycles_spending
+            .settle(record.cycles_reserved, cycles_spent, record.executed_at);
+
+        Ok(())
+    }
+
+    pub fn abort_execution(
+        &mut self,
+        voting_id: &VotingId,
+        cycles_spent: u64,
+    ) -> Result<(), Error> {
+        let record = self.execution_ledger.abort_execution(voting_id)?;
+
+        self.cycles_spending
+            .settle(record.cycles_reserved, cycles_spent, record.executed_at);
+
+        Ok(())
+    }
+
+    // the spending of interrupted programs is unknown, so all the reserved cycles are treated as spent
+    pub fn settle_interrupted_executions(&mut self) {
+        for record in self.execution_ledger.records.iter_mut() {
+            if record.result.is_some() || record.interrupted_by_upgrade {
+                continue;
+            }
+
+            record.interrupted_by_upgrade = true;
+            record.cycles_spent = record.cycles_reserved;
+
+            self.cycles_spending.settle(
+                record.cycles_reserved,
+                record.cycles_reserved,
+                record.executed_at,
+            );
+        }
+    }
+
+    pub fn check_call_controller(
+        &self,
+        caller: &Principal,
+        payload: &UnionCallPayload,
+    ) -> Result<(), Error> {
+        let allowed_endpoints = self
+            .call_controllers
+            .get(caller)
+            .ok_or(Error::AccessDenied)?;
+
+        if let Some(allowed) = allowed_endpoints {
+            if let Some(endpoint) = payload.endpoints().find(|e| !allowed.contains(e)) {
+                return Err(Error::EndpointIsNotAllowed(endpoint.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add_call_controller(
+        &mut self,
+        controller: Principal,
+        allowed_endpoints: Option<HashSet<RemoteCallEndpoint>>,
+    ) -> Result<(), Error> {
+        self.call_controllers.insert(controller, allowed_endpoints);
+
+        Ok(())
+    }
+
+    pub fn remove_call_controller(&mut self, controller: &Principal) -> Result<(), Error> {
+        if !self.call_controllers.contains_key(controller) {
+            return Err(Error::CallControllerDoesNotExist);
+        }
+
+        if self.call_controllers.len() == 1 {
+            return Err(Error::LastCallControllerCannotBeRemoved);
+        }
+
+        self.call_controllers.remove(controller);
+
+        Ok(())
+    }
+
+    pub fn get_call_controllers(&self) -> Vec<CallController> {
+        self.call_controllers
+            .iter()
+            .map(|(controller, allowed_endpoints)| CallController {
+                controller: *controller,
+                allowed_endpoints: allowed_endpoints.clone(),
+            })
+            .collect()
+    }
+}
+
+// every change of the wallet layout gets a new version, so a state saved by older code can be restored
+#[derive(CandidType, Deserialize)]
+pub enum VersionedUnionWallet {
+    V1(UnionWalletV1),
+    V2(UnionWallet),
+}
+
+impl VersionedUnionWallet {
+    pub fn into_latest(self) -> UnionWallet {
+        match self {
+            VersionedUnionWallet::V1(wallet) => wallet.into_v2(),
+            VersionedUnionWallet::V2(wallet) => wallet,
+        }
+    }
+}
+
+// the V1 layout, frozen - tracked tokens had no balance snapshots
+#[derive(CandidType, Deserialize)]
+pub struct TrackedTokenV1 {
+    pub listener_ids: Vec<u64>,
+    pub balance: u64,
+    pub total_inflow: u64,
+    pub total_outflow: u64,
+    pub log: Vec<TreasuryLogEntryV1>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TreasuryLogEntryV1 {
+    pub flow: TreasuryFlow,
+    pub counterparty: Account,
+    pub qty: u64,
+    pub timestamp: u64,
+}
+
+impl TreasuryLogEntryV1 {
+    fn into_v2(self) -> TreasuryLogEntry {
+        TreasuryLogEntry {
+            flow: self.flow,
+            counterparty: self.counterparty,
+            qty: u128::from(self.qty),
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TreasuryV1 {
+    pub tokens: HashMap<Principal, TrackedTokenV1>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PaymentStreamParamsV1 {
+    pub recipient: Principal,
+    pub token: Principal,
+    pub qty: u64,
+    pub period: u64,
+    pub start_at: u64,
+    pub end_at: Option<u64>,
+}
+
+impl PaymentStreamParamsV1 {
+    fn into_v2(self) -> PaymentStreamParams {
+        PaymentStreamParams {
+            recipient: self.recipient,
+            token: self.token,
+            qty: u128::from(self.qty),
+            period: self.period,
+            start_at: self.start_at,
+            end_at: self.end_at,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PaymentStreamV1 {
+    pub id: u64,
+    pub params: PaymentStreamParamsV1,
+    pub next_payment_at: u64,
+    pub cancelled_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PaymentStreamsV1 {
+    pub id_counter: u64,
+    pub streams: HashMap<u64, PaymentStreamV1>,
+    pub disbursements: Vec<DisbursementV1>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DisbursementV1 {
+    pub stream_id: u64,
+    pub scheduled_at: u64,
+    pub executed_at: u64,
+    pub recipient: Principal,
+    pub token: Principal,
+    pub qty: u64,
+    pub result: Result<(), String>,
+}
+
+impl DisbursementV1 {
+    fn into_v2(self) -> Disbursement {
+        Disbursement {
+            stream_id: self.stream_id,
+            scheduled_at: self.scheduled_at,
+            executed_at: self.executed_at,
+            recipient: self.recipient,
+            token: self.token,
+            qty: u128::from(self.qty),
+            result: self.result,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UnionWalletV1 {
+    pub call_controllers: HashMap<Principal, Option<HashSet<RemoteCallEndpoint>>>,
+    pub execution_ledger: ExecutionLedger,
+    pub cycles_spending: CyclesSpending,
+    pub treasury: TreasuryV1,
+    pub payment_streams: PaymentStreamsV1,
+}
+
+impl UnionWalletV1 {
+    fn into_v2(self) -> UnionWallet {
+        let tokens = self
+            .treasury
+            .tokens
+            .into_iter()
+            .map(|(token, tracked)| {
+                let tracked = TrackedToken {
+                    listener_ids: tracked.listener_ids,
+                    balance: u128::from(tracked.balance),
+                    total_inflow: u128::from(tracked.total_inflow),
+                    total_outflow: u128::from(tracked.total_outflow),
+                    log: tracked.log.into_iter().map(|e| e.into_v2()).collect(),
+                    // the first event that comes looks like a gap, so a snapshot is fetched for it
+                    synced_seq: Some(0),
+                    pending_events: Vec::new(),
+                    next_outflow_seq: 0,
+                    next_inflow_seq: 0,
+                };
+
+                (token, tracked)
+            })
+            .collect();
+
+        let streams = self
+            .payment_streams
+            .streams
+            .into_iter()
+            .map(|(id, stream)| {
+                let stream = PaymentStream {
+                    id: stream.id,
+                    params: stream.params.into_v2(),
+                    next_payment_at: stream.next_payment_at,
+                    cancelled_at: stream.cancelled_at,
+                    payment_in_flight: false,
+                    failed_attempts: 0,
+                    last_error: None,
+                };
+
+                (id, stream)
+            })
+            .collect();
+
+        UnionWallet {
+            call_controllers: self.call_controllers,
+            execution_ledger: self.execution_ledger,
+            cycles_spending: self.cycles_spending,
+            treasury: Treasury { tokens },
+            payment_streams: PaymentStreams {
+                id_counter: self.payment_streams.id_counter,
+                streams,
+                disbursements: self
+                    .payment_streams
+                    .disbursements
+                    .into_iter()
+                    .map(|d| d.into_v2())
+                    .collect(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use union_utils::testing::{move_event, principal, stable_round_trip};
+    use union_utils::types::RemoteCallError;
+
+    use super::*;
+
+    fn call(method_name: &str) -> RemoteCallPayload {
+        RemoteCallPayload {
+            endpoint: RemoteCallEndpoint {
+                canister_id: principal(1),
+                method_name: String::from(method_name),
+            },
+            idl_str_args: None,
+            args: Some(RemoteCallArgs::CandidString(String::from("()"))),
+            payment: 0,
+        }
+    }
+
+    fn program(len: usize, parallel_groups: Vec<StepRange>) -> UnionCallPayload {
+        UnionCallPayload {
+            program: (0..len).map(|_| call("step")).collect(),
+            parallel_groups,
+            policy: ExecutionPolicy::Continue,
+            preconditions: Vec::new(),
+            postconditions: Vec::new(),
+            voting_id: voting_id(0),
+        }
+    }
+
+    fn voting_id(idx: usize) -> VotingId {
+        VotingId {
+            union_wallet: principal(1),
+            idx,
+        }
+    }
+
+    fn stream_params(start_at: u64, end_at: Option<u64>) -> PaymentStreamParams {
+        PaymentStreamParams {
+            recipient: principal(2),
+            token: principal(3),
+            qty: 10,
+            period: 100,
+            start_at,
+            end_at,
+        }
+    }
+
+    #[test]
+    fn parallel_groups_become_single_stages() {
+        assert_eq!(program(3, Vec::new()).stages().unwrap(), vec![1, 1, 1]);
+
+        let groups = vec![
+            StepRange { start: 4, end: 6 },
+            StepRange { start: 1, end: 3 },
+        ];
+        assert_eq!(program(6, groups).stages().unwrap(), vec![1, 2, 1, 2]);
+
+        let overlapping = vec![
+            StepRange { start: 0, end: 2 },
+            StepRange { start: 1, end: 3 },
+        ];
+        assert!(matches!(
+            program(3, overlapping).stages(),
+            Err(Error::InvalidParallelGroups)
+        ));
+
+        let empty = vec![StepRange { start: 1, end: 1 }];
+        assert!(matches!(
+            program(3, empty).stages(),
+            Err(Error::InvalidParallelGroups)
+        ));
+
+        let out_of_program = vec![StepRange { start: 1, end: 4 }];
+        assert!(matches!(
+            program(3, out_of_program).stages(),
+            Err(Error::InvalidParallelGroups)
+        ));
+    }
+
+    #[test]
+    fn only_succeeded_steps_are_compensated_in_reverse_order() {
+        let steps = vec![
+            StepResult::Executed(Ok(Vec::new())),
+            StepResult::Executed(Err(RemoteCallError::RemoteCallReject(String::from("")))),
+            StepResult::Executed(Ok(Vec::new())),
+            StepResult::Skipped,
+        ];
+        let compensations: Vec<_> = (0..5)
+            .map(|step| Compensation {
+                step,
+                call: call("undo"),
+            })
+            .collect();
+
+        let to_fire = ExecutionPolicy::Compensate(compensations).compensations_to_fire(&steps);
+        let fired_steps: Vec<_> = to_fire.iter().map(|c| c.step).collect();
+        assert_eq!(fired_steps, vec![2, 0]);
+
+        assert!(ExecutionPolicy::StopOnFirstError
+            .compensations_to_fire(&steps)
+            .is_empty());
+    }
+
+    #[test]
+    fn cycles_are_reserved_within_the_limits_and_settled() {
+        let mut spending = CyclesSpending {
+            limits: SpendingLimits {
+                per_voting: Some(100),
+                per_period: Some(PeriodSpendingLimit {
+                    period: 1000,
+                    limit: 150,
+                }),
+            },
+            ..CyclesSpending::default()
+        };
+
+        assert!(matches!(
+            spending.reserve(101, 0),
+            Err(Error::VotingSpendingLimitExceeded)
+        ));
+
+        spending.reserve(100, 0).unwrap();
+        assert!(matches!(
+            spending.reserve(60, 10),
+            Err(Error::PeriodSpendingLimitExceeded)
+        ));
+
+        // unspent cycles are returned to the period
+        spending.settle(100, 40, 0);
+        assert_eq!(spending.period_spent, 40);
+        assert_eq!(spending.total_spent, 40);
+        spending.reserve(60, 10).unwrap();
+
+        // a new period starts from scratch
+        spending.reserve(100, 1000).unwrap();
+        assert_eq!(spending.period_start, 1000);
+        assert_eq!(spending.period_spent, 100);
+
+        // a reservation of the previous period doesn't affect the current one
+        spending.settle(60, 0, 10);
+        assert_eq!(spending.period_spent, 100);
+        assert_eq!(spending.total_spent, 40);
+    }
+
+    #[test]
+    fn aborted_executions_keep_the_index_consistent() {
+        let mut ledger = ExecutionLedger::default();
+        for idx in 0..3 {
+            ledger
+                .begin_execution(voting_id(idx), Vec::new(), 0, idx as u64)
+                .unwrap();
+        }
+
+        assert!(matches!(
+            ledger.begin_execution(voting_id(1), Vec::new(), 0, 0),
+            Err(Error::VotingAlreadyExecuted)
+        ));
+
+        let aborted = ledger.abort_execution(&voting_id(1)).unwrap();
+        assert_eq!(aborted.voting_id, voting_id(1));
+        assert!(ledger.get_record(&voting_id(1)).is_none());
+        assert_eq!(
+            ledger.get_record(&voting_id(0)).unwrap().voting_id,
+            voting_id(0)
+        );
+        assert_eq!(
+            ledger.get_record(&voting_id(2)).unwrap().voting_id,
+            voting_id(2)
+        );
+
+        // the aborted voting can be executed later
+        ledger
+            .begin_execution(voting_id(1), Vec::new(), 0, 5)
+            .unwrap();
+        assert_eq!(ledger.get_record(&voting_id(1)).unwrap().executed_at, 5);
+        assert!(matches!(
+            ledger.abort_execution(&voting_id(7)),
+            Err(Error::ExecutionRecordDoesNotExist)
+        ));
+    }
+
+    #[test]
+    fn treasury_counts_only_flows_of_the_wallet() {
+        let wallet = principal(1);
+        let other = principal(2);
+        let token = principal(3);
+
+        let mut treasury = Treasury::default();
+        assert!(matches!(
+            treasury.handle_on_move(&token, &wallet, &move_event(0, None, Some(wallet), 1), 0),
+            Err(Error::TokenIsNotTracked)
+        ));
+
+        treasury.track(token).unwrap();
+        treasury
+            .apply_snapshot(
+                &token,
+                &wallet,
+                BalanceSnapshot {
+                    balance: 0,
+                    next_seq: 0,
+                },
+                0,
+            )
+            .unwrap();
+
+        // each listener chains its own events, the transfer to self is delivered by both
+        let events = [
+            move_event(0, Some(wallet), Some(wallet), 50),
+            move_event(0, Some(wallet), Some(wallet), 50),
+            move_event(1, Some(other), Some(wallet), 100),
+            TokenMoveEvent {
+                prev_seq: Some(0),
+                ..move_event(2, Some(wallet), Some(other), 30)
+            },
+            TokenMoveEvent {
+                prev_seq: Some(1),
+                ..move_event(3, None, Some(wallet), 5)
+            },
+        ];
+        for e in events.iter() {
+            treasury.handle_on_move(&token, &wallet, e, 10).unwrap();
+        }
+
+        let tracked = treasury.get_token(&token).unwrap();
+        assert_eq!(tracked.balance, 75);
+        assert_eq!(tracked.total_inflow, 105);
+        assert_eq!(tracked.total_outflow, 30);
+        assert_eq!(tracked.log.len(), 3);
+        assert!(matches!(tracked.log[1].flow, TreasuryFlow::Outflow));
+        assert_eq!(tracked.log[1].counterparty, Some(other));
+    }
+
+    #[test]
+    fn events_before_the_snapshot_are_not_applied_twice() {
+        let wallet = principal(1);
+        let other = principal(2);
+        let token = principal(3);
+
+        let mut treasury = Treasury::default();
+        treasury.track(token).unwrap();
+        treasury.get_token_mut(&token).unwrap().listener_ids = vec![0, 1];
+
+        // both came while the snapshot was being fetched, but only the second isn't included into it
+        treasury
+            .handle_on_move(
+                &token,
+                &wallet,
+                &move_event(5, Some(other), Some(wallet), 10),
+                0,
+            )
+            .unwrap();
+        treasury
+            .handle_on_move(
+                &token,
+                &wallet,
+                &move_event(4, Some(other), Some(wallet), 20),
+                0,
+            )
+            .unwrap();
+        assert!(treasury.is_awaiting_snapshot(&token));
+        assert_eq!(treasury.get_token(&token).unwrap().balance, 0);
+
+        treasury
+            .apply_snapshot(
+                &token,
+                &wallet,
+                BalanceSnapshot {
+                    balance: 120,
+                    next_seq: 5,
+                },
+                0,
+            )
+            .unwrap();
+        assert!(!treasury.is_awaiting_snapshot(&token));
+
+        // a late delivery of an event the snapshot already includes
+        treasury
+            .handle_on_move(
+                &token,
+                &wallet,
+                &move_event(3, Some(other), Some(wallet), 40),
+                0,
+            )
+            .unwrap();
+
+        let tracked = treasury.get_token(&token).unwrap();
+        assert_eq!(tracked.balance, 130);
+        assert_eq!(tracked.total_inflow, 10);
+        assert!(tracked.pending_events.is_empty());
+    }
+
+    #[test]
+    fn redelivered_events_are_ignored_and_gaps_are_resynced() {
+        let wallet = principal(1);
+        let other = principal(2);
+        let token = principal(3);
+        let snapshot = |balance, next_seq| BalanceSnapshot { balance, next_seq };
+
+        let mut treasury = Treasury::default();
+        treasury.track(token).unwrap();
+        treasury.get_token_mut(&token).unwrap().listener_ids = vec![0, 1];
+        treasury
+            .apply_snapshot(&token, &wallet, snapshot(0, 0), 0)
+            .unwrap();
+
+        let inflow = move_event(0, Some(other), Some(wallet), 10);
+        treasury
+            .handle_on_move(&token, &wallet, &inflow, 0)
+            .unwrap();
+        treasury
+            .handle_on_move(&token, &wallet, &inflow, 0)
+            .unwrap();
+        assert_eq!(treasury.get_token(&token).unwrap().total_inflow, 10);
+
+        // events 1 and 2 of the inflow listener never came
+        let after_gap = TokenMoveEvent {
+            prev_seq: Some(2),
+            ..move_event(3, Some(other), Some(wallet), 20)
+        };
+        treasury
+            .handle_on_move(&token, &wallet, &after_gap, 0)
+            .unwrap();
+        assert!(treasury.is_awaiting_snapshot(&token));
+        assert_eq!(treasury.get_token(&token).unwrap().balance, 10);
+
+        treasury
+            .apply_snapshot(&token, &wallet, snapshot(70, 4), 0)
+            .unwrap();
+        assert!(!treasury.is_awaiting_snapshot(&token));
+
+        // a snapshot fetched at the same time comes too late
+        treasury
+            .apply_snapshot(&token, &wallet, snapshot(0, 2), 0)
+            .unwrap();
+
+        treasury
+            .handle_on_move(
+                &token,
+                &wallet,
+                &move_event(4, Some(wallet), Some(other), 5),
+                0,
+            )
+            .unwrap();
+
+        let tracked = treasury.get_token(&token).unwrap();
+        assert_eq!(tracked.balance, 65);
+        assert_eq!(tracked.total_inflow, 10);
+        assert_eq!(tracked.total_outflow, 5);
+        assert!(tracked.pending_events.is_empty());
+    }
+
+    #[test]
+    fn failed_payments_stay_owed_until_they_succeed() {
+        let mut streams = PaymentStreams::default();
+        assert!(matches!(
+            streams.create(PaymentStreamParams {
+                period: 0,
+                ..stream_params(100, None)
+            }),
+            Err(Error::InvalidPaymentStream)
+        ));
+
+        let id = streams.create(stream_params(100, Some(250))).unwrap();
+        assert!(streams.take_due_payments(99).is_empty());
+
+        let mut due = streams.take_due_payments(100);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].scheduled_at, 100);
+        // the same payment isn't picked while it's being sent
+        assert!(streams.take_due_payments(101).is_empty());
+
+        let mut failed = due.remove(0);
+        failed.result = Err(String::from("Insufficient balance"));
+        streams.record_disbursement(failed.clone());
+        streams.take_due_payments(110);
+        streams.record_disbursement(failed);
+
+        let stream = &streams.streams[&id];
+        assert_eq!(stream.next_payment_at, 100);
+        assert_eq!(stream.failed_attempts, 2);
+        assert_eq!(streams.disbursements.len(), 1);
+
+        let retry = streams.take_due_payments(120).remove(0);
+        assert_eq!(retry.scheduled_at, 100);
+        streams.record_disbursement(retry);
+
+        let stream = &streams.streams[&id];
+        assert_eq!(stream.next_payment_at, 200);
+        assert_eq!(stream.failed_attempts, 0);
+        assert!(stream.last_error.is_none());
+        assert_eq!(streams.get_disbursements(Some(id), 0, 10).len(), 2);
+
+        // the stream ends after the period of end_at
+        let last = streams.take_due_payments(300).remove(0);
+        streams.record_disbursement(last);
+        assert!(streams.take_due_payments(400).is_empty());
+        assert!(matches!(
+            streams.cancel(id, 400),
+            Err(Error::PaymentStreamIsNotActive)
+        ));
+    }
+
+    #[test]
+    fn interrupted_and_cancelled_payments() {
+        let mut streams = PaymentStreams::default();
+        let id = streams.create(stream_params(0, None)).unwrap();
+
+        assert_eq!(streams.take_due_payments(0).len(), 1);
+        streams.reset_in_flight_payments();
+        assert_eq!(streams.take_due_payments(0).len(), 1);
+
+        streams.cancel(id, 10).unwrap();
+        streams.reset_in_flight_payments();
+        assert!(streams.take_due_payments(1000).is_empty());
+    }
+
+    #[test]
+    fn retries_are_deduplicated_within_the_token_window() {
+        let mut disbursement = Disbursement {
+            stream_id: 0,
+            scheduled_at: 100,
+            executed_at: 100 + TOKEN_TRANSACTION_WINDOW,
+            recipient: principal(2),
+            token: principal(3),
+            qty: 10,
+            result: Ok(()),
+        };
+        assert_eq!(disbursement.created_at(), Some(100));
+
+        disbursement.executed_at += 1;
+        assert_eq!(disbursement.created_at(), None);
+
+        let duplicate = "(variant { Duplicate = record { tx_id = 5 : nat64 } })"
+            .parse::<IDLArgs>()
+            .unwrap();
+        let too_old = "(variant { TooOld })".parse::<IDLArgs>().unwrap();
+        assert!(is_duplicate_transfer(&duplicate.args[0]));
+        assert!(!is_duplicate_transfer(&too_old.args[0]));
+    }
+
+    #[test]
+    fn call_results_are_compared_by_value() {
+        let raw = "(100 : nat64, \"abc\")"
+            .parse::<IDLArgs>()
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        let expected = RemoteCallArgs::CandidString(String::from("(100 : nat64, \"abc\")"));
+        let other = RemoteCallArgs::CandidString(String::from("(101 : nat64, \"abc\")"));
+
+        assert!(check_call_result_equals(&Ok(raw.clone()), &expected).is_ok());
+        assert!(check_call_result_equals(&Ok(raw), &other).is_err());
+        assert!(check_call_result_equals(
+            &Err(RemoteCallError::RemoteCallReject(String::from("rejected"))),
+            &expected
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn v1_state_is_migrated() {
+        let token = principal(3);
+
+        let mut tokens = HashMap::new();
+        tokens.insert(
+            token,
+            TrackedTokenV1 {
+                listener_ids: vec![0, 1],
+                balance: 50,
+                total_inflow: 70,
+                total_outflow: 20,
+                log: vec![TreasuryLogEntryV1 {
+                    flow: TreasuryFlow::Inflow,
+                    counterparty: None,
+                    qty: 70,
+                    timestamp: 0,
+                }],
+            },
+        );
+
+        let mut streams = HashMap::new();
+        streams.insert(
+            0,
+            PaymentStreamV1 {
+                id: 0,
+                params: PaymentStreamParamsV1 {
+                    recipient: principal(2),
+                    token: principal(3),
+                    qty: 10,
+                    period: 100,
+                    start_at: 100,
+                    end_at: None,
+                },
+                next_payment_at: 300,
+                cancelled_at: None,
+            },
+        );
+
+        let mut call_controllers = HashMap::new();
+        call_controllers.insert(principal(4), None);
+
+        let v1 = VersionedUnionWallet::V1(UnionWalletV1 {
+            call_controllers,
+            execution_ledger: ExecutionLedger::default(),
+            cycles_spending: CyclesSpending::default(),
+            treasury: TreasuryV1 { tokens },
+            payment_streams: PaymentStreamsV1 {
+                id_counter: 1,
+                streams,
+                disbursements: Vec::new(),
+            },
+        });
+
+        let wallet = stable_round_trip(v1).into_latest();
+
+        let tracked = wallet.treasury.get_token(&token).unwrap();
+        assert_eq!(tracked.balance, 50);
+        assert_eq!(tracked.log[0].qty, 70);
+        assert_eq!(tracked.synced_seq, Some(0));
+        assert_eq!(tracked.next_inflow_seq, 0);
+        assert_eq!(wallet.payment_streams.id_counter, 1);
+        assert_eq!(wallet.payment_streams.streams[&0].next_payment_at, 300);
+        assert_eq!(wallet.payment_streams.streams[&0].params.qty, 10);
+        assert!(!wallet.payment_streams.streams[&0].payment_in_flight);
+        assert_eq!(wallet.call_controllers.len(), 1);
+
+        // and the migrated state is saved as the latest version
+        let wallet = stable_round_trip(VersionedUnionWallet::V2(wallet)).into_latest();
+        assert_eq!(wallet.treasury.get_token(&token).unwrap().total_inflow, 70);
+    }
+}
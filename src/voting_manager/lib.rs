@@ -1,14 +1,21 @@
 use std::collections::HashMap;
 
 use ic_cdk::api::time;
-use ic_cdk::caller;
 use ic_cdk::export::Principal;
-use ic_cdk_macros::{init, update};
+use ic_cdk::storage::{stable_restore, stable_save};
+use ic_cdk::{call, caller};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 
-use union_utils::fns::{log, remote_call};
-use union_utils::types::{RemoteCallEndpoint, RemoteCallPayload};
+use union_utils::fns::{
+    check_args_against_interface, get_candid_interface, log, parse_idl_args, remote_call,
+};
+use union_utils::types::{DecodedRemoteCallResult, RemoteCallPayload, VotingId};
 
-use crate::utils::{Error, NewVotingParams, Vote, Voting, VotingConfigType, VotingManager};
+use crate::utils::{
+    Error, NewVotingParams, Page, PayloadDiagnostic, PayloadEntryReport, UpdateVotingParams,
+    VersionedVotingManager, Vote, VoteHistoryPage, VoteReceipt, VotingFilter, VotingManager,
+    VotingSummaryPage,
+};
 
 mod utils;
 
@@ -21,49 +28,247 @@ fn init() {
     unsafe {
         VOTING_MANAGER = Some(VotingManager {
             votings: HashMap::new(),
-            voting_config: HashMap::new(),
             membership_guards: HashMap::new(),
+            voting_config_types: HashMap::new(),
+            voting_configs: HashMap::new(),
             event_listeners: HashMap::new(),
         })
     }
 }
 
+#[pre_upgrade]
+fn pre_upgrade_hook() {
+    log("voting_manager.pre_upgrade()");
+
+    let voting_manager = unsafe { VOTING_MANAGER.take().unwrap() };
+
+    stable_save((VersionedVotingManager::V2(voting_manager),))
+        .expect("Unable to save the voting manager to stable memory");
+}
+
+#[post_upgrade]
+fn post_upgrade_hook() {
+    log("voting_manager.post_upgrade()");
+
+    let (versioned,): (VersionedVotingManager,) =
+        stable_restore().expect("Unable to restore the voting manager from stable memory");
+
+    unsafe { VOTING_MANAGER = Some(versioned.into_latest()) }
+}
+
 #[update]
-fn create_voting(params: NewVotingParams) -> Result<(), Error> {
-    log("votings.do_vote()");
+async fn create_voting(params: NewVotingParams) -> Result<VotingId, Error> {
+    log("voting_manager.create_voting()");
+
+    let is_caller_a_member = is_member(params.union_wallet, caller()).await;
 
     let voting_manager = unsafe { VOTING_MANAGER.as_mut().unwrap() };
-    match voting_manager.voting_config.get(&params.union_wallet) {
-        None => Err(Error::VotingConfigDoesNotExist),
-        Some(config) => match config.data {
-            VotingConfigType::None(c) => c,
-        },
-    }
+
+    voting_manager.create_voting(caller(), time() as i64, params, is_caller_a_member)
 }
 
 #[update]
-async fn execute() -> Result<Option<Vec<u8>>, Error> {
-    log("votings.execute()");
+fn update_voting(voting_id: VotingId, params: UpdateVotingParams) -> Result<(), Error> {
+    log("voting_manager.update_voting()");
 
-    let voting = unsafe { VOTING.as_mut().unwrap() };
+    let voting_manager = unsafe { VOTING_MANAGER.as_mut().unwrap() };
 
-    voting.execute(time())?;
+    voting_manager
+        .update_voting(voting_id, params, time() as i64, caller())
+        .map(|_| ())
+}
 
-    let entry_opt = voting.payload.clone();
+#[update]
+async fn delete_voting(voting_id: VotingId) -> Result<(), Error> {
+    log("voting_manager.delete_voting()");
 
-    if let Some(entry) = entry_opt {
-        return remote_call(entry)
-            .await
-            .map_err(Error::VotingExecutionError)
-            .map(Some);
+    let is_caller_a_member = is_member(voting_id.union_wallet, caller()).await;
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_mut().unwrap() };
+
+    voting_manager
+        .delete_voting(voting_id, caller(), is_caller_a_member)
+        .map(|_| ())
+}
+
+#[update]
+async fn do_vote(voting_id: VotingId, vote: Vote) -> Result<(), Error> {
+    log("voting_manager.do_vote()");
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_ref().unwrap() };
+    let created_at = voting_manager.get_voting(&voting_id)?.created_at;
+
+    // the voting power is taken at the voting's creation, so it can't be bought in afterwards
+    let voter = caller();
+    let (voting_power, total_voting_power) =
+        voting_power_at(voting_id.union_wallet, voter, created_at).await?;
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_mut().unwrap() };
+
+    voting_manager.vote(
+        voting_id,
+        &voter,
+        voting_power,
+        total_voting_power,
+        vote,
+        time() as i64,
+    )
+}
+
+#[query]
+fn get_votings(union_wallet: Principal, filter: VotingFilter, page: Page) -> VotingSummaryPage {
+    log("voting_manager.get_votings()");
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_ref().unwrap() };
+
+    voting_manager.get_votings(&union_wallet, &filter, &page)
+}
+
+#[query]
+fn get_vote(voting_id: VotingId, voter: Principal) -> Result<Option<VoteReceipt>, Error> {
+    log("voting_manager.get_vote()");
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_ref().unwrap() };
+
+    voting_manager.get_vote(&voting_id, &voter)
+}
+
+#[query]
+fn get_voting_history(union_wallet: Principal, voter: Principal, page: Page) -> VoteHistoryPage {
+    log("voting_manager.get_voting_history()");
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_ref().unwrap() };
+
+    voting_manager.get_voting_history(&union_wallet, &voter, &page)
+}
+
+#[query]
+fn get_execute_result(voting_id: VotingId) -> Result<Vec<DecodedRemoteCallResult>, Error> {
+    log("voting_manager.get_execute_result()");
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_ref().unwrap() };
+
+    voting_manager.get_execute_result(&voting_id)
+}
+
+#[update]
+async fn validate_payload(
+    union_wallet: Principal,
+    payload: Vec<RemoteCallPayload>,
+    check_interfaces: bool,
+) -> Result<Vec<PayloadEntryReport>, Error> {
+    log("voting_manager.validate_payload()");
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_ref().unwrap() };
+
+    let mut reports = voting_manager.validate_payload(&union_wallet, &payload)?;
+
+    if !check_interfaces {
+        return Ok(reports);
     }
 
-    Ok(None)
+    // only entries with parseable args are checked against the target's interface
+    for (entry, report) in payload.into_iter().zip(reports.iter_mut()) {
+        let idl_args = match entry.call_args().and_then(|args| parse_idl_args(&args)) {
+            Ok(a) => a,
+            Err(_) => continue,
+        };
+
+        let result = match get_candid_interface(entry.endpoint.canister_id).await {
+            Ok(interface) => check_args_against_interface(
+                idl_args,
+                interface.as_str(),
+                entry.endpoint.method_name.as_str(),
+            ),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            report
+                .diagnostics
+                .push(PayloadDiagnostic::InterfaceError(e));
+        }
+    }
+
+    Ok(reports)
 }
 
 #[update]
-fn update() -> Result<(), Error> {
-    if caller != self.proposer {
-        return Err(Error::CallerIsNotCreator);
+async fn execute(voting_id: VotingId) -> Result<Vec<DecodedRemoteCallResult>, Error> {
+    log("voting_manager.execute()");
+
+    let union_wallet = voting_id.union_wallet;
+    let is_caller_a_member = is_member(union_wallet, caller()).await;
+
+    let voting_manager = unsafe { VOTING_MANAGER.as_mut().unwrap() };
+    voting_manager.execute(
+        voting_id.clone(),
+        time() as i64,
+        caller(),
+        is_caller_a_member,
+    )?;
+
+    let payload = voting_manager.get_voting(&voting_id)?.payload.clone();
+
+    let mut results = Vec::new();
+    for entry in payload.into_iter() {
+        results.push(remote_call(entry).await);
     }
+
+    // the voting is looked up again, since it could be deleted in between the calls - ids of
+    // the votings are never reused, so the results can't be recorded into another one
+    let voting_manager = unsafe { VOTING_MANAGER.as_mut().unwrap() };
+    voting_manager.record_execute_result(voting_id.clone(), results)?;
+
+    voting_manager.get_execute_result(&voting_id)
+}
+
+// members are the ones who have some voting power according to the union's membership guard
+async fn is_member(union_wallet: Principal, principal: Principal) -> bool {
+    let voting_manager = unsafe { VOTING_MANAGER.as_ref().unwrap() };
+
+    let guard = match voting_manager
+        .membership_guards
+        .get(&union_wallet)
+        .and_then(|g| g.data)
+    {
+        Some(guard) => guard,
+        None => return false,
+    };
+
+    let voting_power = call::<_, (u64,)>(
+        guard,
+        "_union_voting_power_of_at",
+        (principal, time() as i64),
+    )
+    .await;
+
+    matches!(voting_power, Ok((vp,)) if vp > 0)
+}
+
+// the voter's and the total voting power, according to the union's membership guard
+async fn voting_power_at(
+    union_wallet: Principal,
+    principal: Principal,
+    timestamp: i64,
+) -> Result<(u64, u64), Error> {
+    let voting_manager = unsafe { VOTING_MANAGER.as_ref().unwrap() };
+
+    let guard = voting_manager
+        .membership_guards
+        .get(&union_wallet)
+        .and_then(|g| g.data)
+        .ok_or(Error::MembershipGuardDoesNotExist)?;
+
+    let (voting_power,) =
+        call::<_, (u64,)>(guard, "_union_voting_power_of_at", (principal, timestamp))
+            .await
+            .map_err(|(_, e)| Error::VotingPowerUnavailable(e))?;
+
+    let (total_voting_power,) =
+        call::<_, (u64,)>(guard, "_union_total_voting_power_at", (timestamp,))
+            .await
+            .map_err(|(_, e)| Error::VotingPowerUnavailable(e))?;
+
+    Ok((voting_power, total_voting_power))
 }
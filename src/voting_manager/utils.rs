@@ -1,12 +1,12 @@
-use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter::FromIterator;
 
 use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
 
-use union_utils::fns::is_passing_threshold;
+use union_utils::fns::{decode_result, is_passing_threshold, validate_args};
 use union_utils::types::{
-    Controlled, RemoteCallEndpoint, RemoteCallError, RemoteCallPayload, RemoteCallResult, VotingId,
+    CandidInterfaceError, Controlled, DecodedRemoteCallResult, RemoteCallEndpoint, RemoteCallError,
+    RemoteCallPayload, RemoteCallResult, VotingId,
 };
 
 #[derive(Clone, Debug, CandidType, Deserialize, PartialOrd, PartialEq)]
@@ -35,9 +35,13 @@ pub enum Error {
     VotingThresholdError,
     VotingThresholdNotPassed,
     VotingAlreadyExecuted,
+    VotingIsNotExecuted,
     CallerIsNotCreator,
     VotingExecutionError(RemoteCallError),
     VotingConfigDoesNotExist,
+    InvalidPayloadArgs(RemoteCallError),
+    MembershipGuardDoesNotExist,
+    VotingPowerUnavailable(String),
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -56,16 +60,43 @@ pub struct Voting {
     pub title: String,
     pub description: String,
     pub payload: Vec<RemoteCallPayload>,
-    pub execute_result: Vec<RemoteCallResult>,
+    pub execute_result: Vec<DecodedRemoteCallResult>,
 
     pub union_wallet: Principal,
     pub proposer: Principal,
     pub status: VotingStatus,
 
-    pub voters_for: HashMap<Principal, i64>,
+    pub voters_for: HashMap<Principal, VoteEntry>,
     pub voting_power_for: u64,
-    pub voters_against: HashMap<Principal, i64>,
+    pub voters_against: HashMap<Principal, VoteEntry>,
     pub voting_power_against: u64,
+    // abstentions don't affect the outcome, they are only kept as the voter's record
+    pub voters_abstained: HashMap<Principal, VoteEntry>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VoteEntry {
+    pub voting_power: u64,
+    pub timestamp: i64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VoteReceipt {
+    pub vote: Vote,
+    pub voting_power: u64,
+    pub timestamp: i64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VoteHistoryEntry {
+    pub voting_id: VotingId,
+    pub receipt: VoteReceipt,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VoteHistoryPage {
+    pub entries: Vec<VoteHistoryEntry>,
+    pub total: usize,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -91,6 +122,56 @@ pub struct NewVotingParams {
     pub can_vote: WhoCanVote,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum PayloadDiagnostic {
+    ArgsError(RemoteCallError),
+    EndpointIsNotAllowed,
+    InterfaceError(CandidInterfaceError),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PayloadEntryReport {
+    pub endpoint: RemoteCallEndpoint,
+    pub diagnostics: Vec<PayloadDiagnostic>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VotingFilter {
+    pub status: Option<VotingStatus>,
+    pub proposer: Option<Principal>,
+    pub created_at: Option<Interval<i64>>,
+    pub endpoint: Option<RemoteCallEndpoint>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VotingSummary {
+    pub id: VotingId,
+    pub created_at: i64,
+    pub updated_at: i64,
+
+    pub title: String,
+    pub proposer: Principal,
+    pub status: VotingStatus,
+    pub endpoints: Vec<RemoteCallEndpoint>,
+
+    pub voters_for_count: usize,
+    pub voting_power_for: u64,
+    pub voters_against_count: usize,
+    pub voting_power_against: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct VotingSummaryPage {
+    pub entries: Vec<VotingSummary>,
+    pub total: usize,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct UpdateVotingParams {
     pub approval: Option<f64>,
@@ -134,6 +215,7 @@ impl Voting {
             voting_power_for: 0,
             voters_against: HashMap::new(),
             voting_power_against: 0,
+            voters_abstained: HashMap::new(),
         }
     }
 
@@ -159,17 +241,24 @@ impl Voting {
             return Err(Error::VotingAlreadyExecuted);
         }
 
-        self.remove_prev_vote(voter, vote_voting_power);
+        self.remove_prev_vote(voter);
+
+        let entry = VoteEntry {
+            voting_power: vote_voting_power,
+            timestamp,
+        };
 
         match vote {
-            Vote::Abstain => (),
+            Vote::Abstain => {
+                self.voters_abstained.insert(*voter, entry);
+            }
             Vote::For => {
                 self.voting_power_for += vote_voting_power;
-                self.voters_for.insert(voter.clone(), timestamp);
+                self.voters_for.insert(*voter, entry);
             }
             Vote::Against => {
                 self.voting_power_against += vote_voting_power;
-                self.voters_against.insert(voter.clone(), timestamp);
+                self.voters_against.insert(*voter, entry);
             }
         };
 
@@ -260,20 +349,91 @@ impl Voting {
         Ok(())
     }
 
-    fn remove_prev_vote(&mut self, voter: &Principal, voting_power: u64) {
-        let vote_for = self.voters_for.get(voter);
-        if vote_for.is_some() {
-            self.voters_for.remove(voter);
-            self.voting_power_for -= voting_power;
+    pub fn matches(&self, filter: &VotingFilter) -> bool {
+        if let Some(status) = &filter.status {
+            if self.status != *status {
+                return false;
+            }
+        }
+
+        if let Some(proposer) = &filter.proposer {
+            if self.proposer != *proposer {
+                return false;
+            }
+        }
+
+        if let Some(created_at) = &filter.created_at {
+            if !created_at.contains(self.created_at) {
+                return false;
+            }
+        }
+
+        if let Some(endpoint) = &filter.endpoint {
+            if !self.payload.iter().any(|p| p.endpoint == *endpoint) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn to_summary(&self, id: VotingId) -> VotingSummary {
+        VotingSummary {
+            id,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+
+            title: self.title.clone(),
+            proposer: self.proposer,
+            status: self.status.clone(),
+            endpoints: self.payload.iter().map(|p| p.endpoint.clone()).collect(),
+
+            voters_for_count: self.voters_for.len(),
+            voting_power_for: self.voting_power_for,
+            voters_against_count: self.voters_against.len(),
+            voting_power_against: self.voting_power_against,
+        }
+    }
+
+    pub fn get_vote(&self, voter: &Principal) -> Option<VoteReceipt> {
+        if let Some(entry) = self.voters_for.get(voter) {
+            return Some(VoteReceipt {
+                vote: Vote::For,
+                voting_power: entry.voting_power,
+                timestamp: entry.timestamp,
+            });
+        }
+
+        if let Some(entry) = self.voters_against.get(voter) {
+            return Some(VoteReceipt {
+                vote: Vote::Against,
+                voting_power: entry.voting_power,
+                timestamp: entry.timestamp,
+            });
+        }
+
+        self.voters_abstained.get(voter).map(|entry| VoteReceipt {
+            vote: Vote::Abstain,
+            voting_power: entry.voting_power,
+            timestamp: entry.timestamp,
+        })
+    }
+
+    // the voting power is taken from the previous entry, since it could change between votes
+    fn remove_prev_vote(&mut self, voter: &Principal) {
+        if let Some(entry) = self.voters_for.remove(voter) {
+            self.voting_power_for -= entry.voting_power;
 
             return;
         }
 
-        let vote_against = self.voters_against.get(voter);
-        if vote_against.is_some() {
-            self.voters_against.remove(voter);
-            self.voting_power_against -= voting_power;
+        if let Some(entry) = self.voters_against.remove(voter) {
+            self.voting_power_against -= entry.voting_power;
+
+            return;
         }
+
+        self.voters_abstained.remove(voter);
     }
 }
 
@@ -285,18 +445,28 @@ pub enum VotingConfigType {
 }
 
 impl VotingConfigType {
+    pub fn is_endpoint_allowed(&self, endpoint: &RemoteCallEndpoint) -> bool {
+        match self {
+            VotingConfigType::None => true,
+            VotingConfigType::Whitelist(wl) => wl.contains(endpoint),
+            VotingConfigType::Blacklist(bl) => !bl.contains(endpoint),
+        }
+    }
+
     pub fn is_allowed_to_create(&self, params: &NewVotingParams) -> bool {
         match self {
             VotingConfigType::None => true,
             VotingConfigType::Whitelist(wl) => {
                 // TODO: make it faster
-                let endpoints = HashSet::from_iter(params.payload.iter().map(|it| it.endpoint));
+                let endpoints =
+                    HashSet::from_iter(params.payload.iter().map(|it| it.endpoint.clone()));
 
                 endpoints.is_subset(wl)
             }
             VotingConfigType::Blacklist(bl) => {
                 // TODO: make it faster
-                let endpoints = HashSet::from_iter(params.payload.iter().map(|it| it.endpoint));
+                let endpoints =
+                    HashSet::from_iter(params.payload.iter().map(|it| it.endpoint.clone()));
 
                 endpoints.is_disjoint(bl)
             }
@@ -419,11 +589,11 @@ impl VotingConfig {
         updater: &Principal,
         voting: &Voting,
     ) -> bool {
-        if voting.proposer != updater.clone() {
+        if voting.proposer != *updater {
             return false;
         }
 
-        let endpoints = params.payload.unwrap_or(voting.payload.clone());
+        let endpoints = params.payload.as_ref().unwrap_or(&voting.payload);
 
         let mut result = true;
 
@@ -526,9 +696,17 @@ impl VotingConfig {
     }
 }
 
+// ids are never reused, so deleting a voting doesn't change the ids of the others,
+// e.g. of one whose execution is awaited
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct UnionVotings {
+    pub next_idx: usize,
+    pub votings: BTreeMap<usize, Voting>,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct VotingManager {
-    pub votings: HashMap<Principal, Vec<Voting>>,
+    pub votings: HashMap<Principal, UnionVotings>,
     pub membership_guards: HashMap<Principal, Controlled<Option<Principal>>>,
     pub voting_config_types: HashMap<Principal, Controlled<VotingConfigType>>,
     pub voting_configs: HashMap<Principal, Controlled<VotingConfig>>,
@@ -556,23 +734,24 @@ impl VotingManager {
             return Err(Error::VotingIsRejected); // TODO: another error here please
         }
 
+        if let Some(config_type) = self.voting_config_types.get(&params.union_wallet) {
+            if !config_type.data.is_allowed_to_create(&params) {
+                return Err(Error::VotingIsRejected); // TODO: another error here please
+            }
+        }
+
+        validate_payload_args(&params.payload)?;
+
+        let union_wallet = params.union_wallet;
         let voting = Voting::new(proposer, timestamp, params);
 
-        let votings = match self.votings.get_mut(&params.union_wallet) {
-            None => {
-                self.votings.insert(params.union_wallet.clone(), Vec::new());
-                self.votings.get_mut(&params.union_wallet).unwrap()
-            }
-            Some(v) => v,
-        };
+        let votings = self.votings.entry(union_wallet).or_default();
 
-        let idx = votings.len();
-        votings.push(voting);
+        let idx = votings.next_idx;
+        votings.next_idx += 1;
+        votings.votings.insert(idx, voting);
 
-        Ok(VotingId {
-            union_wallet: params.union_wallet.clone(),
-            idx,
-        })
+        Ok(VotingId { union_wallet, idx })
     }
 
     pub fn delete_voting(
@@ -581,29 +760,15 @@ impl VotingManager {
         caller: Principal,
         is_caller_a_member: bool,
     ) -> Result<Voting, Error> {
-        let votings = self
-            .votings
-            .get_mut(&voting_id.union_wallet)
-            .ok_or(Error::VotingDoesNotExist)?;
+        let (voting, config) = self.get_voting_and_config_mut(&voting_id)?;
 
-        if votings.len() <= voting_id.idx {
-            Err(Error::VotingDoesNotExist)
+        if config.is_allowed_to_delete(&caller, voting, is_caller_a_member) {
+            self.votings
+                .get_mut(&voting_id.union_wallet)
+                .and_then(|v| v.votings.remove(&voting_id.idx))
+                .ok_or(Error::VotingDoesNotExist)
         } else {
-            let voting = votings.get(voting_id.idx).unwrap();
-
-            let config = self
-                .voting_configs
-                .get(&voting.union_wallet)
-                .ok_or(Error::VotingConfigDoesNotExist)?;
-
-            if config
-                .data
-                .is_allowed_to_delete(&caller, voting, is_caller_a_member)
-            {
-                Ok(votings.remove(voting_id.idx))
-            } else {
-                Err(Error::VotingConfigDoesNotExist) // TODO: we need another error here
-            }
+            Err(Error::VotingConfigDoesNotExist) // TODO: we need another error here
         }
     }
 
@@ -614,14 +779,13 @@ impl VotingManager {
         timestamp: i64,
         caller: Principal,
     ) -> Result<Voting, Error> {
-        let voting = self.get_voting_mut(voting_id)?;
+        if let Some(payload) = &params.payload {
+            validate_payload_args(payload)?;
+        }
 
-        let config = self
-            .voting_configs
-            .get(&voting.union_wallet)
-            .ok_or(Error::VotingConfigDoesNotExist)?;
+        let (voting, config) = self.get_voting_and_config_mut(&voting_id)?;
 
-        if config.data.is_allowed_to_update(&params, &caller, voting) {
+        if config.is_allowed_to_update(&params, &caller, voting) {
             voting.update(params, timestamp)?;
 
             Ok(voting.clone())
@@ -639,23 +803,16 @@ impl VotingManager {
         vote: Vote,
         timestamp: i64,
     ) -> Result<(), Error> {
-        let voting = self.get_voting_mut(voting_id)?;
+        let (voting, config) = self.get_voting_and_config_mut(&voting_id)?;
 
-        let config = self
-            .voting_configs
-            .get(&voting.union_wallet)
-            .ok_or(Error::VotingConfigDoesNotExist)?;
-
-        if config.data.is_allowed_to_vote(voter, voting) {
+        if config.is_allowed_to_vote(voter, voting) {
             voting.vote(
                 voter,
                 vote_voting_power,
                 total_voting_power,
                 vote,
                 timestamp,
-            );
-
-            Ok(())
+            )
         } else {
             Err(Error::VotingIsRejected) // TODO: another error here
         }
@@ -668,35 +825,207 @@ impl VotingManager {
         caller: Principal,
         is_caller_a_member: bool,
     ) -> Result<(), Error> {
-        let voting = self.get_voting_mut(voting_id)?;
+        let (voting, config) = self.get_voting_and_config_mut(&voting_id)?;
+
+        if config.is_allowed_to_execute(&caller, voting, is_caller_a_member) {
+            voting.execute(timestamp)
+        } else {
+            Err(Error::VotingIsRejected) // TODO: another error please
+        }
+    }
+
+    // borrows the votings and the configs separately, so the config can be read alongside
+    fn get_voting_and_config_mut(
+        &mut self,
+        id: &VotingId,
+    ) -> Result<(&mut Voting, &VotingConfig), Error> {
+        let voting = self
+            .votings
+            .get_mut(&id.union_wallet)
+            .and_then(|v| v.votings.get_mut(&id.idx))
+            .ok_or(Error::VotingDoesNotExist)?;
 
         let config = self
             .voting_configs
             .get(&voting.union_wallet)
             .ok_or(Error::VotingConfigDoesNotExist)?;
 
-        if config
-            .data
-            .is_allowed_to_execute(&caller, voting, is_caller_a_member)
-        {
-            voting.execute(timestamp);
-            Ok(())
-        } else {
-            Err(Error::VotingIsRejected) // TODO: another error please
-        }
+        Ok((voting, &config.data))
     }
 
     pub fn get_voting_mut(&mut self, id: VotingId) -> Result<&mut Voting, Error> {
-        let votings = self
-            .votings
+        self.votings
             .get_mut(&id.union_wallet)
-            .map_or(Err(Error::VotingDoesNotExist), |v| Ok(v))?;
+            .and_then(|v| v.votings.get_mut(&id.idx))
+            .ok_or(Error::VotingDoesNotExist)
+    }
 
-        votings
-            .get_mut(id.idx)
-            .map_or(Err(Error::VotingDoesNotExist), |v| Ok(v))
+    pub fn get_voting(&self, id: &VotingId) -> Result<&Voting, Error> {
+        self.votings
+            .get(&id.union_wallet)
+            .and_then(|v| v.votings.get(&id.idx))
+            .ok_or(Error::VotingDoesNotExist)
     }
 
+    pub fn get_votings(
+        &self,
+        union_wallet: &Principal,
+        filter: &VotingFilter,
+        page: &Page,
+    ) -> VotingSummaryPage {
+        let votings = match self.votings.get(union_wallet) {
+            None => {
+                return VotingSummaryPage {
+                    entries: Vec::new(),
+                    total: 0,
+                }
+            }
+            Some(v) => v,
+        };
+
+        let matching: Vec<_> = votings
+            .votings
+            .iter()
+            .filter(|(_, voting)| voting.matches(filter))
+            .collect();
+
+        let entries = matching
+            .iter()
+            .skip(page.offset)
+            .take(page.limit)
+            .map(|(idx, voting)| {
+                voting.to_summary(VotingId {
+                    union_wallet: *union_wallet,
+                    idx: **idx,
+                })
+            })
+            .collect();
+
+        VotingSummaryPage {
+            entries,
+            total: matching.len(),
+        }
+    }
+
+    pub fn get_vote(
+        &self,
+        voting_id: &VotingId,
+        voter: &Principal,
+    ) -> Result<Option<VoteReceipt>, Error> {
+        let voting = self.get_voting(voting_id)?;
+
+        Ok(voting.get_vote(voter))
+    }
+
+    pub fn get_voting_history(
+        &self,
+        union_wallet: &Principal,
+        voter: &Principal,
+        page: &Page,
+    ) -> VoteHistoryPage {
+        let votings = match self.votings.get(union_wallet) {
+            None => {
+                return VoteHistoryPage {
+                    entries: Vec::new(),
+                    total: 0,
+                }
+            }
+            Some(v) => v,
+        };
+
+        let history: Vec<_> = votings
+            .votings
+            .iter()
+            .filter_map(|(idx, voting)| {
+                voting.get_vote(voter).map(|receipt| VoteHistoryEntry {
+                    voting_id: VotingId {
+                        union_wallet: *union_wallet,
+                        idx: *idx,
+                    },
+                    receipt,
+                })
+            })
+            .collect();
+
+        let total = history.len();
+
+        VoteHistoryPage {
+            entries: history
+                .into_iter()
+                .skip(page.offset)
+                .take(page.limit)
+                .collect(),
+            total,
+        }
+    }
+
+    pub fn validate_payload(
+        &self,
+        union_wallet: &Principal,
+        payload: &[RemoteCallPayload],
+    ) -> Result<Vec<PayloadEntryReport>, Error> {
+        if !self.voting_configs.contains_key(union_wallet) {
+            return Err(Error::VotingConfigDoesNotExist);
+        }
+
+        let config_type = self.voting_config_types.get(union_wallet);
+
+        let reports = payload
+            .iter()
+            .enumerate()
+            .map(|(step, entry)| {
+                let mut diagnostics = Vec::new();
+
+                if let Err(e) = entry
+                    .call_args()
+                    .and_then(|args| validate_args(&args, step))
+                {
+                    diagnostics.push(PayloadDiagnostic::ArgsError(e));
+                }
+
+                if let Some(c) = config_type {
+                    if !c.data.is_endpoint_allowed(&entry.endpoint) {
+                        diagnostics.push(PayloadDiagnostic::EndpointIsNotAllowed);
+                    }
+                }
+
+                PayloadEntryReport {
+                    endpoint: entry.endpoint.clone(),
+                    diagnostics,
+                }
+            })
+            .collect();
+
+        Ok(reports)
+    }
+
+    pub fn record_execute_result(
+        &mut self,
+        voting_id: VotingId,
+        results: Vec<RemoteCallResult>,
+    ) -> Result<(), Error> {
+        let voting = self.get_voting_mut(voting_id)?;
+
+        if voting.status != VotingStatus::Executed {
+            return Err(Error::VotingIsNotExecuted);
+        }
+
+        voting.execute_result = results.into_iter().map(decode_result).collect();
+
+        Ok(())
+    }
+
+    pub fn get_execute_result(
+        &self,
+        voting_id: &VotingId,
+    ) -> Result<Vec<DecodedRemoteCallResult>, Error> {
+        let voting = self.get_voting(voting_id)?;
+
+        Ok(voting.execute_result.clone())
+    }
+
+    // used once the votings emit events
+    #[allow(dead_code)]
     pub fn get_listeners(&self, event_type: VotingEventType) -> Vec<RemoteCallEndpoint> {
         self.event_listeners
             .get(&event_type)
@@ -704,22 +1033,38 @@ impl VotingManager {
     }
 }
 
+fn validate_payload_args(payload: &[RemoteCallPayload]) -> Result<(), Error> {
+    for (step, entry) in payload.iter().enumerate() {
+        entry
+            .call_args()
+            .and_then(|args| validate_args(&args, step))
+            .map_err(Error::InvalidPayloadArgs)?;
+    }
+
+    Ok(())
+}
+
+// votings don't emit events to the listeners yet
+#[allow(dead_code)]
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct VotingCreatedEventPayload {
     pub id: VotingId,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct VotingUpdatedEventPayload {
     pub id: VotingId,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct StatusChangedEventPayload {
     pub id: VotingId,
     pub status: VotingStatus,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct VotePlacedEventPayload {
     pub id: VotingId,
@@ -736,6 +1081,7 @@ pub enum VotingEventType {
     VotePlaced,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum VotingEvent {
     VotingCreated(VotingCreatedEventPayload),
@@ -743,3 +1089,557 @@ pub enum VotingEvent {
     StateChanged(StatusChangedEventPayload),
     VotePlaced(VotePlacedEventPayload),
 }
+
+// votings are the union's history, so a layout change adds a variant instead of dropping them
+#[derive(CandidType, Deserialize)]
+pub enum VersionedVotingManager {
+    V1(VotingManagerV1),
+    V2(VotingManager),
+}
+
+impl VersionedVotingManager {
+    pub fn into_latest(self) -> VotingManager {
+        match self {
+            VersionedVotingManager::V1(voting_manager) => voting_manager.into_v2(),
+            VersionedVotingManager::V2(voting_manager) => voting_manager,
+        }
+    }
+}
+
+// the V1 layout, frozen - votings were kept in a list, so their ids were positions in it
+#[derive(CandidType, Deserialize)]
+pub struct VotingManagerV1 {
+    pub votings: HashMap<Principal, Vec<Voting>>,
+    pub membership_guards: HashMap<Principal, Controlled<Option<Principal>>>,
+    pub voting_config_types: HashMap<Principal, Controlled<VotingConfigType>>,
+    pub voting_configs: HashMap<Principal, Controlled<VotingConfig>>,
+
+    pub event_listeners: HashMap<VotingEventType, HashSet<RemoteCallEndpoint>>,
+}
+
+impl VotingManagerV1 {
+    fn into_v2(self) -> VotingManager {
+        VotingManager {
+            votings: self
+                .votings
+                .into_iter()
+                .map(|(union_wallet, votings)| {
+                    // ids were positions, so the migrated votings keep them
+                    let votings = UnionVotings {
+                        next_idx: votings.len(),
+                        votings: votings.into_iter().enumerate().collect(),
+                    };
+
+                    (union_wallet, votings)
+                })
+                .collect(),
+            membership_guards: self.membership_guards,
+            voting_config_types: self.voting_config_types,
+            voting_configs: self.voting_configs,
+            event_listeners: self.event_listeners,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_cdk::export::candid::{decode_one, encode_args, encode_one};
+
+    use union_utils::types::RemoteCallArgs;
+
+    use super::*;
+
+    fn endpoint(canister: u8, method_name: &str) -> RemoteCallEndpoint {
+        RemoteCallEndpoint {
+            canister_id: Principal::from_slice(&[canister]),
+            method_name: String::from(method_name),
+        }
+    }
+
+    fn call(endpoint: RemoteCallEndpoint, args: &str) -> RemoteCallPayload {
+        RemoteCallPayload {
+            endpoint,
+            idl_str_args: None,
+            args: Some(RemoteCallArgs::CandidString(String::from(args))),
+            payment: 0,
+        }
+    }
+
+    // a union whose config lets anyone create, vote, delete and execute any voting
+    fn permissive_manager(union_wallet: Principal) -> VotingManager {
+        let any = Interval { min: 0.0, max: 1.0 };
+        let config = VotingConfig {
+            default: RemoteCallVotingParams {
+                approval: any.clone(),
+                rejection: any.clone(),
+                quorum: any.clone(),
+                consensus: any,
+                duration: None,
+                can_vote: PossibleVoter::Any,
+                can_create: VotingCharacter::All,
+                can_delete: VotingCharacter::All,
+                can_execute: VotingCharacter::All,
+            },
+            custom: HashMap::new(),
+        };
+
+        let mut voting_configs = HashMap::new();
+        voting_configs.insert(union_wallet, Controlled::by_no_one(config));
+
+        VotingManager {
+            votings: HashMap::new(),
+            membership_guards: HashMap::new(),
+            voting_config_types: HashMap::new(),
+            voting_configs,
+            event_listeners: HashMap::new(),
+        }
+    }
+
+    fn new_voting(
+        union_wallet: Principal,
+        title: &str,
+        payload: Vec<RemoteCallPayload>,
+    ) -> NewVotingParams {
+        NewVotingParams {
+            union_wallet,
+            approval: 0.5,
+            rejection: 0.5,
+            quorum: 0.0,
+            consensus: 0.5,
+            duration: None,
+            title: String::from(title),
+            description: String::new(),
+            payload,
+            can_vote: WhoCanVote::Member,
+        }
+    }
+
+    fn no_filter() -> VotingFilter {
+        VotingFilter {
+            status: None,
+            proposer: None,
+            created_at: None,
+            endpoint: None,
+        }
+    }
+
+    fn ids(page: &VotingSummaryPage) -> Vec<usize> {
+        page.entries.iter().map(|s| s.id.idx).collect()
+    }
+
+    #[test]
+    fn state_survives_stable_memory_round_trip() {
+        let union_wallet = Principal::from_slice(&[1]);
+        let proposer = Principal::from_slice(&[2]);
+
+        let params = NewVotingParams {
+            union_wallet,
+            approval: 0.5,
+            rejection: 0.5,
+            quorum: 0.3,
+            consensus: 0.6,
+            duration: Some(100),
+            title: String::from("Test"),
+            description: String::from("Test voting"),
+            payload: Vec::new(),
+            can_vote: WhoCanVote::Member,
+        };
+
+        let mut voting = Voting::new(proposer, 10, params);
+        voting.voters_for.insert(
+            proposer,
+            VoteEntry {
+                voting_power: 5,
+                timestamp: 20,
+            },
+        );
+        voting.voting_power_for = 5;
+
+        let mut union_votings = UnionVotings::default();
+        union_votings.votings.insert(0, voting);
+        union_votings.next_idx = 1;
+
+        let mut votings = HashMap::new();
+        votings.insert(union_wallet, union_votings);
+
+        let voting_manager = VotingManager {
+            votings,
+            membership_guards: HashMap::new(),
+            voting_config_types: HashMap::new(),
+            voting_configs: HashMap::new(),
+            event_listeners: HashMap::new(),
+        };
+
+        let bytes = encode_one(VersionedVotingManager::V2(voting_manager)).unwrap();
+        let restored = decode_one::<VersionedVotingManager>(&bytes)
+            .unwrap()
+            .into_latest();
+
+        let voting = restored
+            .get_voting(&VotingId {
+                union_wallet,
+                idx: 0,
+            })
+            .unwrap();
+
+        assert_eq!(voting.title, "Test");
+        assert_eq!(voting.proposer, proposer);
+        assert_eq!(voting.status, VotingStatus::Proposal);
+        assert_eq!(voting.voting_power_for, 5);
+        assert_eq!(voting.voters_for.get(&proposer).unwrap().timestamp, 20);
+    }
+
+    #[test]
+    fn v1_state_is_migrated() {
+        let union_wallet = Principal::from_slice(&[1]);
+        let alice = Principal::from_slice(&[2]);
+
+        let mut manager = permissive_manager(union_wallet);
+        for title in ["first", "second"].iter() {
+            let params = new_voting(union_wallet, title, Vec::new());
+            manager.create_voting(alice, 10, params, true).unwrap();
+        }
+
+        let mut votings = HashMap::new();
+        votings.insert(
+            union_wallet,
+            manager.votings[&union_wallet]
+                .votings
+                .values()
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+
+        let voting_manager = VotingManagerV1 {
+            votings,
+            membership_guards: manager.membership_guards,
+            voting_config_types: manager.voting_config_types,
+            voting_configs: manager.voting_configs,
+            event_listeners: manager.event_listeners,
+        };
+
+        let bytes = encode_one(VersionedVotingManager::V1(voting_manager)).unwrap();
+        let mut restored = decode_one::<VersionedVotingManager>(&bytes)
+            .unwrap()
+            .into_latest();
+
+        assert_eq!(restored.votings[&union_wallet].next_idx, 2);
+
+        let second = VotingId {
+            union_wallet,
+            idx: 1,
+        };
+        assert_eq!(restored.get_voting(&second).unwrap().title, "second");
+
+        // a new voting doesn't take the id of a migrated one
+        let params = new_voting(union_wallet, "third", Vec::new());
+        let third = restored.create_voting(alice, 20, params, true).unwrap();
+        assert_eq!(third.idx, 2);
+    }
+
+    #[test]
+    fn votings_are_filtered_and_paginated() {
+        let union_wallet = Principal::from_slice(&[1]);
+        let alice = Principal::from_slice(&[2]);
+        let bob = Principal::from_slice(&[3]);
+
+        let mut manager = permissive_manager(union_wallet);
+        let first = vec![call(endpoint(5, "a"), "()")];
+        let second = vec![call(endpoint(6, "b"), "()")];
+
+        manager
+            .create_voting(alice, 10, new_voting(union_wallet, "first", first), true)
+            .unwrap();
+        let approved = manager
+            .create_voting(bob, 20, new_voting(union_wallet, "second", second), true)
+            .unwrap();
+        manager
+            .create_voting(
+                alice,
+                30,
+                new_voting(union_wallet, "third", Vec::new()),
+                true,
+            )
+            .unwrap();
+        manager
+            .vote(approved, &alice, 6, 10, Vote::For, 25)
+            .unwrap();
+
+        let page = |filter: VotingFilter, offset: usize, limit: usize| {
+            manager.get_votings(&union_wallet, &filter, &Page { offset, limit })
+        };
+
+        let by_alice = page(
+            VotingFilter {
+                proposer: Some(alice),
+                ..no_filter()
+            },
+            0,
+            10,
+        );
+        assert_eq!(ids(&by_alice), vec![0, 2]);
+        assert_eq!(by_alice.total, 2);
+
+        let approved = page(
+            VotingFilter {
+                status: Some(VotingStatus::Approved),
+                ..no_filter()
+            },
+            0,
+            10,
+        );
+        assert_eq!(ids(&approved), vec![1]);
+        assert_eq!(approved.entries[0].voting_power_for, 6);
+
+        let created_later = page(
+            VotingFilter {
+                created_at: Some(Interval { min: 15, max: 30 }),
+                ..no_filter()
+            },
+            0,
+            10,
+        );
+        assert_eq!(ids(&created_later), vec![1, 2]);
+
+        let calling_a = page(
+            VotingFilter {
+                endpoint: Some(endpoint(5, "a")),
+                ..no_filter()
+            },
+            0,
+            10,
+        );
+        assert_eq!(ids(&calling_a), vec![0]);
+
+        let second_page = page(no_filter(), 1, 1);
+        assert_eq!(ids(&second_page), vec![1]);
+        assert_eq!(second_page.total, 3);
+
+        let unknown = manager.get_votings(
+            &alice,
+            &no_filter(),
+            &Page {
+                offset: 0,
+                limit: 10,
+            },
+        );
+        assert!(unknown.entries.is_empty());
+        assert_eq!(unknown.total, 0);
+    }
+
+    #[test]
+    fn votes_are_receipted_and_listed_in_the_history() {
+        let union_wallet = Principal::from_slice(&[1]);
+        let alice = Principal::from_slice(&[2]);
+        let bob = Principal::from_slice(&[3]);
+
+        let mut manager = permissive_manager(union_wallet);
+        let mut voting_ids = Vec::new();
+        for title in ["first", "second", "third"].iter() {
+            let params = new_voting(union_wallet, title, Vec::new());
+            voting_ids.push(manager.create_voting(bob, 10, params, true).unwrap());
+        }
+
+        manager
+            .vote(voting_ids[0].clone(), &alice, 3, 10, Vote::For, 20)
+            .unwrap();
+        manager
+            .vote(voting_ids[1].clone(), &alice, 3, 10, Vote::Against, 21)
+            .unwrap();
+        manager
+            .vote(voting_ids[2].clone(), &alice, 3, 10, Vote::Abstain, 22)
+            .unwrap();
+
+        let receipt = manager.get_vote(&voting_ids[1], &alice).unwrap().unwrap();
+        assert_eq!(receipt.vote, Vote::Against);
+        assert_eq!(receipt.voting_power, 3);
+        assert_eq!(receipt.timestamp, 21);
+        assert!(manager.get_vote(&voting_ids[1], &bob).unwrap().is_none());
+
+        // abstentions are recorded, but don't count
+        let abstained = manager.get_voting(&voting_ids[2]).unwrap();
+        assert_eq!(abstained.voting_power_for, 0);
+        assert_eq!(abstained.voting_power_against, 0);
+
+        // a changed vote replaces the previous one
+        manager
+            .vote(voting_ids[0].clone(), &alice, 3, 10, Vote::Abstain, 23)
+            .unwrap();
+        assert_eq!(
+            manager.get_voting(&voting_ids[0]).unwrap().voting_power_for,
+            0
+        );
+
+        let history = manager.get_voting_history(
+            &union_wallet,
+            &alice,
+            &Page {
+                offset: 1,
+                limit: 5,
+            },
+        );
+        assert_eq!(history.total, 3);
+        let votes: Vec<(usize, Vote)> = history
+            .entries
+            .into_iter()
+            .map(|e| (e.voting_id.idx, e.receipt.vote))
+            .collect();
+        assert_eq!(votes, vec![(1, Vote::Against), (2, Vote::Abstain)]);
+
+        let history = manager.get_voting_history(
+            &union_wallet,
+            &bob,
+            &Page {
+                offset: 0,
+                limit: 5,
+            },
+        );
+        assert_eq!(history.total, 0);
+    }
+
+    #[test]
+    fn payloads_are_validated_before_the_voting() {
+        let union_wallet = Principal::from_slice(&[1]);
+        let alice = Principal::from_slice(&[2]);
+
+        let mut manager = permissive_manager(union_wallet);
+        let mut whitelist = HashSet::new();
+        whitelist.insert(endpoint(5, "a"));
+        manager.voting_config_types.insert(
+            union_wallet,
+            Controlled::by_no_one(VotingConfigType::Whitelist(whitelist)),
+        );
+
+        let payload = vec![
+            call(endpoint(5, "a"), "(1 : nat64)"),
+            call(endpoint(5, "a"), "(1 : nat64"),
+            call(endpoint(6, "b"), "()"),
+        ];
+
+        let reports = manager.validate_payload(&union_wallet, &payload).unwrap();
+        assert!(reports[0].diagnostics.is_empty());
+        assert!(matches!(
+            reports[1].diagnostics.as_slice(),
+            [PayloadDiagnostic::ArgsError(
+                RemoteCallError::UnableToParseArgs
+            )]
+        ));
+        assert!(matches!(
+            reports[2].diagnostics.as_slice(),
+            [PayloadDiagnostic::EndpointIsNotAllowed]
+        ));
+
+        assert!(matches!(
+            manager.validate_payload(&alice, &payload),
+            Err(Error::VotingConfigDoesNotExist)
+        ));
+
+        let invalid = new_voting(union_wallet, "invalid", payload[..2].to_vec());
+        assert!(matches!(
+            manager.create_voting(alice, 10, invalid, true),
+            Err(Error::InvalidPayloadArgs(_))
+        ));
+
+        let not_allowed = new_voting(union_wallet, "not allowed", payload[2..].to_vec());
+        assert!(matches!(
+            manager.create_voting(alice, 10, not_allowed, true),
+            Err(Error::VotingIsRejected)
+        ));
+
+        let valid = new_voting(union_wallet, "valid", payload[..1].to_vec());
+        manager.create_voting(alice, 10, valid, true).unwrap();
+    }
+
+    #[test]
+    fn execution_results_are_recorded() {
+        let union_wallet = Principal::from_slice(&[1]);
+        let alice = Principal::from_slice(&[2]);
+
+        let mut manager = permissive_manager(union_wallet);
+        let params = new_voting(union_wallet, "call", vec![call(endpoint(5, "a"), "()")]);
+        let voting_id = manager.create_voting(alice, 10, params, true).unwrap();
+
+        assert!(matches!(
+            manager.execute(voting_id.clone(), 20, alice, true),
+            Err(Error::VotingThresholdNotPassed)
+        ));
+        assert!(matches!(
+            manager.record_execute_result(voting_id.clone(), Vec::new()),
+            Err(Error::VotingIsNotExecuted)
+        ));
+
+        manager
+            .vote(voting_id.clone(), &alice, 6, 10, Vote::For, 20)
+            .unwrap();
+        manager.execute(voting_id.clone(), 30, alice, true).unwrap();
+
+        let results = vec![
+            Ok(encode_args((String::from("done"),)).unwrap()),
+            Err(RemoteCallError::RemoteCallReject(String::from("rejected"))),
+        ];
+        manager
+            .record_execute_result(voting_id.clone(), results)
+            .unwrap();
+
+        let recorded = manager.get_execute_result(&voting_id).unwrap();
+        assert!(matches!(
+            &recorded[0],
+            Ok(output) if output.decoded.as_deref() == Some("(\"done\")")
+        ));
+        assert!(matches!(
+            &recorded[1],
+            Err(RemoteCallError::RemoteCallReject(e)) if e == "rejected"
+        ));
+
+        assert!(matches!(
+            manager.execute(voting_id, 40, alice, true),
+            Err(Error::VotingAlreadyExecuted)
+        ));
+    }
+
+    #[test]
+    fn deleted_votings_dont_shift_the_ids() {
+        let union_wallet = Principal::from_slice(&[1]);
+        let alice = Principal::from_slice(&[2]);
+
+        let mut manager = permissive_manager(union_wallet);
+        let mut voting_ids = Vec::new();
+        for title in ["first", "second", "third"].iter() {
+            let params = new_voting(union_wallet, title, Vec::new());
+            voting_ids.push(manager.create_voting(alice, 10, params, true).unwrap());
+        }
+
+        manager
+            .vote(voting_ids[1].clone(), &alice, 6, 10, Vote::For, 20)
+            .unwrap();
+        manager
+            .execute(voting_ids[1].clone(), 30, alice, true)
+            .unwrap();
+
+        // deleted while the execution of the second one is awaited
+        manager
+            .delete_voting(voting_ids[0].clone(), alice, true)
+            .unwrap();
+
+        let results = vec![Ok(encode_args(()).unwrap())];
+        manager
+            .record_execute_result(voting_ids[1].clone(), results)
+            .unwrap();
+
+        assert_eq!(manager.get_execute_result(&voting_ids[1]).unwrap().len(), 1);
+        assert!(manager
+            .get_execute_result(&voting_ids[2])
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            manager.get_voting(&voting_ids[0]),
+            Err(Error::VotingDoesNotExist)
+        ));
+
+        // the deleted id isn't given to a new voting
+        let params = new_voting(union_wallet, "fourth", Vec::new());
+        let fourth = manager.create_voting(alice, 40, params, true).unwrap();
+        assert_eq!(fourth.idx, 3);
+        assert_eq!(manager.get_voting(&voting_ids[2]).unwrap().title, "third");
+    }
+}
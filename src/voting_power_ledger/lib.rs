@@ -1,14 +1,12 @@
-use std::collections::HashMap;
-
-use ic_cdk::api::time;
 use ic_cdk::caller;
 use ic_cdk::export::candid::Principal;
-use ic_cdk_macros::{init, query, update};
+use ic_cdk::storage::{stable_restore, stable_save};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 
-use union_utils::fns::log;
-use union_utils::types::TokenMoveEvent;
+use union_utils::fns::{fetch_missing_events, log};
+use union_utils::types::{TokenMoveEvent, MAX_EVENTS_PAGE};
 
-use crate::utils::{Error, GlobalVotingPowerLedger};
+use crate::utils::{Error, GlobalVotingPowerLedger, VersionedGlobalVotingPowerLedger};
 
 mod utils;
 
@@ -19,10 +17,30 @@ fn init() {
     log("voting_power_ledger.init()");
 
     unsafe {
-        LEDGER = Some(GlobalVotingPowerLedger(HashMap::new()));
+        LEDGER = Some(GlobalVotingPowerLedger::default());
     }
 }
 
+#[pre_upgrade]
+fn pre_upgrade_hook() {
+    log("voting_power_ledger.pre_upgrade()");
+
+    let ledger = unsafe { LEDGER.take().unwrap() };
+
+    stable_save((VersionedGlobalVotingPowerLedger::V2(ledger),))
+        .expect("Unable to save the ledger to stable memory");
+}
+
+#[post_upgrade]
+fn post_upgrade_hook() {
+    log("voting_power_ledger.post_upgrade()");
+
+    let (versioned,): (VersionedGlobalVotingPowerLedger,) =
+        stable_restore().expect("Unable to restore the ledger from stable memory");
+
+    unsafe { LEDGER = Some(versioned.into_latest()) }
+}
+
 #[query]
 fn voting_power_of_at(emitter_id: Principal, p: Principal, t: i64) -> Result<u64, Error> {
     log("voting_power_ledger.voting_power_of_at()");
@@ -48,44 +66,48 @@ fn total_voting_power_at(emitter_id: Principal, t: i64) -> Result<u64, Error> {
 }
 
 #[update]
-fn handle_on_move(event: TokenMoveEvent) -> Result<(), Error> {
+async fn handle_on_move(event: TokenMoveEvent) -> Result<(), Error> {
     log("voting_power_ledger.handle_on_move()");
 
     let ledger = unsafe { LEDGER.as_mut().unwrap() };
     let canister_id = caller();
-    let time = time();
-
-    // transfer
-    if event.from.is_some() && event.to.is_some() {
-        let from = event.from.unwrap();
-        let to = event.to.unwrap();
 
-        let from_vp = ledger.get_voting_power_at(&canister_id, &from, time)?;
-        let to_vp = ledger.get_voting_power_at(&canister_id, &to, time)?;
+    match ledger.apply_event(canister_id, &event) {
+        // some events got lost on the way - pull them, this event included, from the emitter
+        Err(Error::EventSequenceGap { .. }) => resync_emitter(canister_id).await,
+        result => result,
+    }
+}
 
-        ledger.supply_voting_power_entry(canister_id.clone(), from, from_vp - event.qty, time)?;
-        ledger.supply_voting_power_entry(canister_id, to, to_vp + event.qty, time)?;
+#[update]
+async fn resync(emitter_id: Principal) -> Result<(), Error> {
+    log("voting_power_ledger.resync()");
 
-    // burn
-    } else if event.from.is_some() {
-        let from = event.from.unwrap();
+    resync_emitter(emitter_id).await
+}
 
-        let from_vp = ledger.get_voting_power_at(&canister_id, &from, time)?;
-        let total_vp = ledger.get_total_voting_power_at(&canister_id, time)?;
+// pulls the events the ledger hasn't applied yet from the emitter's log - an emitter which isn't
+// tracked yet has nothing to resync against, it gets tracked with the next event it sends
+async fn resync_emitter(emitter_id: Principal) -> Result<(), Error> {
+    if !unsafe { LEDGER.as_ref().unwrap() }.is_tracked(&emitter_id) {
+        return Err(Error::EmitterNotTracked);
+    }
 
-        ledger.supply_voting_power_entry(canister_id.clone(), from, from_vp - event.qty, time)?;
-        ledger.supply_total_voting_power_entry(canister_id, total_vp - event.qty, time)?;
+    loop {
+        let from_seq = unsafe { LEDGER.as_ref().unwrap() }.next_seq(&emitter_id);
 
-    // mint
-    } else if event.to.is_some() {
-        let to = event.to.unwrap();
+        let events = fetch_missing_events(emitter_id, from_seq, MAX_EVENTS_PAGE)
+            .await
+            .map_err(Error::EventFetchFailed)?;
 
-        let to_vp = ledger.get_voting_power_at(&canister_id, &to, time)?;
-        let total_vp = ledger.get_total_voting_power_at(&canister_id, time)?;
+        let ledger = unsafe { LEDGER.as_mut().unwrap() };
+        for event in events.iter() {
+            ledger.apply_event(emitter_id, event)?;
+        }
 
-        ledger.supply_voting_power_entry(canister_id.clone(), to, to_vp + event.qty, time)?;
-        ledger.supply_total_voting_power_entry(canister_id, total_vp + event.qty, time)?;
+        // stop on the last page or if the emitter doesn't let us advance
+        if events.len() < MAX_EVENTS_PAGE as usize || ledger.next_seq(&emitter_id) == from_seq {
+            return Ok(());
+        }
     }
-
-    Ok(())
 }
@@ -1,6 +1,8 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+use union_utils::types::{EventSequenceTracker, SequenceCheck, TokenMoveEvent};
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum Error {
@@ -8,6 +10,11 @@ pub enum Error {
     EmitterNotRegistered,
     AccessDenied,
     HistoryLookupFatalError,
+    EventSequenceGap { from_seq: u64, to_seq: u64 },
+    EventFetchFailed(String),
+    EmitterNotTracked,
+    InsufficientVotingPower,
+    VotingPowerOverflow,
 }
 
 #[derive(Clone, Debug, Default, CandidType, Deserialize)]
@@ -25,21 +32,101 @@ pub struct VotingPowerLedger {
     total_voting_power: VotingPowerHistory,
 }
 
-// token canister -> ledger
-#[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct GlobalVotingPowerLedger(pub HashMap<Principal, VotingPowerLedger>);
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct GlobalVotingPowerLedger {
+    // token canister -> ledger
+    pub ledgers: HashMap<Principal, VotingPowerLedger>,
+    pub sequences: EventSequenceTracker,
+}
 
 impl GlobalVotingPowerLedger {
+    pub fn next_seq(&self, canister_id: &Principal) -> u64 {
+        self.sequences.next_seq(canister_id)
+    }
+
+    pub fn is_tracked(&self, canister_id: &Principal) -> bool {
+        self.sequences.is_tracked(canister_id)
+    }
+
+    // events have to be applied in the order they were emitted - on a gap the missing ones should be fetched first
+    pub fn apply_event(
+        &mut self,
+        canister_id: Principal,
+        event: &TokenMoveEvent,
+    ) -> Result<(), Error> {
+        // an emitter which isn't tracked yet, e.g. one migrated from V1, is tracked from the first
+        // event it sends - the history the ledger already has for it stays and nothing is replayed,
+        // since the ledger can't tell which of the emitter's events that history already contains
+        if self.is_tracked(&canister_id) {
+            match self.sequences.check(&canister_id, event) {
+                SequenceCheck::AlreadyApplied => return Ok(()),
+                SequenceCheck::Gap { from_seq, to_seq } => {
+                    return Err(Error::EventSequenceGap { from_seq, to_seq })
+                }
+                SequenceCheck::InOrder => {}
+            }
+        }
+
+        self.ledgers.entry(canister_id).or_default();
+
+        let time = event.timestamp as i64;
+        // token amounts are nat, but voting power stays nat64
+        let qty = u64::try_from(event.qty).map_err(|_| Error::VotingPowerOverflow)?;
+
+        // transfer
+        if let (Some(from), Some(to)) = (event.from, event.to) {
+            let from_vp = self.get_voting_power_at(&canister_id, &from, time)?;
+            let from_vp = from_vp
+                .checked_sub(qty)
+                .ok_or(Error::InsufficientVotingPower)?;
+            let to_vp = self.get_voting_power_at(&canister_id, &to, time)?;
+            let to_vp = to_vp.checked_add(qty).ok_or(Error::VotingPowerOverflow)?;
+
+            self.supply_voting_power_entry(canister_id, from, from_vp, time)?;
+            self.supply_voting_power_entry(canister_id, to, to_vp, time)?;
+
+        // burn
+        } else if let Some(from) = event.from {
+            let from_vp = self.get_voting_power_at(&canister_id, &from, time)?;
+            let total_vp = self.get_total_voting_power_at(&canister_id, time)?;
+
+            // the total can't be lower than any single voting power
+            let from_vp = from_vp
+                .checked_sub(qty)
+                .ok_or(Error::InsufficientVotingPower)?;
+            let total_vp = total_vp
+                .checked_sub(qty)
+                .ok_or(Error::InsufficientVotingPower)?;
+
+            self.supply_voting_power_entry(canister_id, from, from_vp, time)?;
+            self.supply_total_voting_power_entry(canister_id, total_vp, time)?;
+
+        // mint
+        } else if let Some(to) = event.to {
+            let to_vp = self.get_voting_power_at(&canister_id, &to, time)?;
+            let total_vp = self.get_total_voting_power_at(&canister_id, time)?;
+
+            // any single voting power fits as long as the total does
+            let total_vp = total_vp
+                .checked_add(qty)
+                .ok_or(Error::VotingPowerOverflow)?;
+
+            self.supply_voting_power_entry(canister_id, to, to_vp + qty, time)?;
+            self.supply_total_voting_power_entry(canister_id, total_vp, time)?;
+        }
+
+        self.sequences.mark_applied(canister_id, event);
+
+        Ok(())
+    }
+
     pub fn supply_total_voting_power_entry(
         &mut self,
         canister_id: Principal,
         new_total_voting_power: u64,
         timestamp: i64,
     ) -> Result<(), Error> {
-        let ledger = self
-            .0
-            .entry(canister_id)
-            .or_insert_with(VotingPowerLedger::default);
+        let ledger = self.ledgers.entry(canister_id).or_default();
 
         let entry = VotingPowerEntry {
             timestamp,
@@ -59,14 +146,8 @@ impl GlobalVotingPowerLedger {
         voting_power: u64,
         timestamp: i64,
     ) -> Result<(), Error> {
-        let ledger = self
-            .0
-            .entry(canister_id)
-            .or_insert_with(VotingPowerLedger::default);
-        let history = ledger
-            .history
-            .entry(account_id)
-            .or_insert_with(VotingPowerHistory::new);
+        let ledger = self.ledgers.entry(canister_id).or_default();
+        let history = ledger.history.entry(account_id).or_default();
 
         let entry = VotingPowerEntry {
             timestamp,
@@ -84,7 +165,10 @@ impl GlobalVotingPowerLedger {
         account_id: &Principal,
         timestamp: i64,
     ) -> Result<u64, Error> {
-        let ledger = self.0.get(canister_id).ok_or(Error::EmitterNotRegistered)?;
+        let ledger = self
+            .ledgers
+            .get(canister_id)
+            .ok_or(Error::EmitterNotRegistered)?;
 
         match ledger.history.get(account_id) {
             None => Ok(0),
@@ -101,7 +185,10 @@ impl GlobalVotingPowerLedger {
         canister_id: &Principal,
         timestamp: i64,
     ) -> Result<u64, Error> {
-        let ledger = self.0.get(canister_id).ok_or(Error::EmitterNotRegistered)?;
+        let ledger = self
+            .ledgers
+            .get(canister_id)
+            .ok_or(Error::EmitterNotRegistered)?;
 
         lookup_history_at(&ledger.total_voting_power, timestamp).ok_or(Error::HistoryLookupFatalError)
     }
@@ -167,3 +254,194 @@ fn lookup_history_at(history: &VotingPowerHistory, timestamp: i64) -> Option<u64
         }
     }
 }
+
+// histories are kept forever, so old layouts have to stay restorable - see into_latest()
+#[derive(CandidType, Deserialize)]
+pub enum VersionedGlobalVotingPowerLedger {
+    V1(GlobalVotingPowerLedgerV1),
+    V2(GlobalVotingPowerLedger),
+}
+
+impl VersionedGlobalVotingPowerLedger {
+    pub fn into_latest(self) -> GlobalVotingPowerLedger {
+        match self {
+            VersionedGlobalVotingPowerLedger::V1(ledger) => ledger.into_v2(),
+            VersionedGlobalVotingPowerLedger::V2(ledger) => ledger,
+        }
+    }
+}
+
+// the V1 layout, frozen - it didn't know which events were applied
+#[derive(CandidType, Deserialize)]
+pub struct GlobalVotingPowerLedgerV1(pub HashMap<Principal, VotingPowerLedger>);
+
+impl GlobalVotingPowerLedgerV1 {
+    // no emitter is tracked yet, so each of them is tracked from its next event on top of the kept history
+    fn into_v2(self) -> GlobalVotingPowerLedger {
+        GlobalVotingPowerLedger {
+            ledgers: self.0,
+            sequences: EventSequenceTracker::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use union_utils::testing::{move_event, principal, stable_round_trip};
+
+    use super::*;
+
+    #[test]
+    fn state_survives_stable_memory_round_trip() {
+        let emitter = principal(1);
+        let account = principal(2);
+
+        let mut ledger = GlobalVotingPowerLedger::default();
+        ledger
+            .supply_voting_power_entry(emitter, account, 10, 100)
+            .unwrap();
+        ledger
+            .supply_voting_power_entry(emitter, account, 20, 200)
+            .unwrap();
+        ledger
+            .supply_total_voting_power_entry(emitter, 50, 200)
+            .unwrap();
+
+        let restored =
+            stable_round_trip(VersionedGlobalVotingPowerLedger::V2(ledger)).into_latest();
+
+        let before_first = restored.get_voting_power_at(&emitter, &account, 50);
+        let after_last = restored.get_voting_power_at(&emitter, &account, 250);
+        let total = restored.get_total_voting_power_at(&emitter, 250);
+
+        assert_eq!(before_first.unwrap(), 0);
+        assert_eq!(after_last.unwrap(), 20);
+        assert_eq!(total.unwrap(), 50);
+    }
+
+    #[test]
+    fn events_are_applied_once_and_in_order() {
+        let emitter = principal(1);
+        let account = principal(2);
+        let mint = |seq: u64, qty| TokenMoveEvent {
+            timestamp: 100 * (seq + 1),
+            ..move_event(seq, None, Some(account), qty)
+        };
+
+        let mut ledger = GlobalVotingPowerLedger::default();
+        ledger.apply_event(emitter, &mint(0, 10)).unwrap();
+        ledger.apply_event(emitter, &mint(0, 10)).unwrap();
+
+        let gap = ledger.apply_event(emitter, &mint(2, 30));
+        assert!(matches!(
+            gap,
+            Err(Error::EventSequenceGap {
+                from_seq: 1,
+                to_seq: 2
+            })
+        ));
+
+        ledger.apply_event(emitter, &mint(1, 20)).unwrap();
+        ledger.apply_event(emitter, &mint(2, 30)).unwrap();
+
+        assert_eq!(ledger.next_seq(&emitter), 3);
+        assert_eq!(
+            ledger.get_voting_power_at(&emitter, &account, 300).unwrap(),
+            60
+        );
+        assert_eq!(ledger.get_total_voting_power_at(&emitter, 300).unwrap(), 60);
+    }
+
+    #[test]
+    fn v1_voting_power_survives_the_upgrade() {
+        let emitter = principal(1);
+        let account = principal(2);
+        let other = principal(3);
+        let event = |seq, timestamp, from, to, qty| TokenMoveEvent {
+            timestamp,
+            ..move_event(seq, from, to, qty)
+        };
+
+        let mut v1 = GlobalVotingPowerLedger::default();
+        v1.apply_event(emitter, &event(0, 100, None, Some(account), 10))
+            .unwrap();
+        v1.apply_event(emitter, &event(1, 200, None, Some(account), 20))
+            .unwrap();
+
+        let v1 = VersionedGlobalVotingPowerLedger::V1(GlobalVotingPowerLedgerV1(v1.ledgers));
+        let mut ledger = stable_round_trip(v1).into_latest();
+
+        assert!(!ledger.is_tracked(&emitter));
+        assert_eq!(
+            ledger.get_voting_power_at(&emitter, &account, 200).unwrap(),
+            30
+        );
+
+        // the upgraded token's log starts over, its first event is applied on top of the old history
+        let transfer = event(0, 300, Some(account), Some(other), 5);
+        ledger.apply_event(emitter, &transfer).unwrap();
+        ledger.apply_event(emitter, &transfer).unwrap();
+
+        assert!(ledger.is_tracked(&emitter));
+        assert_eq!(
+            ledger.get_voting_power_at(&emitter, &account, 300).unwrap(),
+            25
+        );
+        assert_eq!(
+            ledger.get_voting_power_at(&emitter, &other, 300).unwrap(),
+            5
+        );
+        assert_eq!(ledger.get_total_voting_power_at(&emitter, 300).unwrap(), 30);
+
+        assert!(matches!(
+            ledger.apply_event(emitter, &event(2, 400, Some(account), None, 5)),
+            Err(Error::EventSequenceGap {
+                from_seq: 1,
+                to_seq: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn moving_more_than_the_voting_power_is_rejected() {
+        let emitter = principal(1);
+        let account = principal(2);
+        let other = principal(3);
+        let event = |seq, from, to, qty| TokenMoveEvent {
+            timestamp: 100,
+            ..move_event(seq, from, to, qty)
+        };
+
+        let mut ledger = GlobalVotingPowerLedger::default();
+        ledger
+            .apply_event(emitter, &event(0, None, Some(account), 10))
+            .unwrap();
+
+        assert!(matches!(
+            ledger.apply_event(emitter, &event(1, Some(account), Some(other), 11)),
+            Err(Error::InsufficientVotingPower)
+        ));
+        assert!(matches!(
+            ledger.apply_event(emitter, &event(1, Some(account), None, 11)),
+            Err(Error::InsufficientVotingPower)
+        ));
+
+        // the total voting power has to fit into nat64, even if token amounts don't
+        assert!(matches!(
+            ledger.apply_event(emitter, &event(1, None, Some(other), u128::from(u64::MAX))),
+            Err(Error::VotingPowerOverflow)
+        ));
+        assert!(matches!(
+            ledger.apply_event(emitter, &event(1, None, Some(other), u128::MAX)),
+            Err(Error::VotingPowerOverflow)
+        ));
+
+        // nothing is written and the event can still be applied once the ledger is fixed
+        assert_eq!(ledger.next_seq(&emitter), 1);
+        assert_eq!(
+            ledger.get_voting_power_at(&emitter, &other, 100).unwrap(),
+            0
+        );
+        assert_eq!(ledger.get_total_voting_power_at(&emitter, 100).unwrap(), 10);
+    }
+}